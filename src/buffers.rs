@@ -0,0 +1,90 @@
+use super::indexed_string::IndexedString;
+use std::sync::Arc;
+
+// Names an immutable buffer in a `BufferRegistry`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct BufferId(usize);
+
+impl BufferId {
+    // For writing IDs to files. Only meaningful to the registry they came
+    // from, or one rebuilt in the same order.
+    pub fn index(&self) -> usize {
+        return self.0;
+    }
+
+    pub fn from_index(index: usize) -> Self {
+        return BufferId(index);
+    }
+}
+
+// Read-only text a document refers to but doesn't own outright: buffers
+// adopted from other documents, pasted clips and included files. Buffers are
+// reference counted, so sharing one between documents copies nothing.
+#[derive(Clone, Default)]
+pub struct BufferRegistry {
+    buffers: Vec<Arc<IndexedString>>,
+}
+
+impl BufferRegistry {
+    pub fn new() -> Self {
+        BufferRegistry { buffers: vec![] }
+    }
+
+    pub fn get(&self, id: BufferId) -> &Arc<IndexedString> {
+        return &self.buffers[id.0];
+    }
+
+    // Only for the buffer a document types into, which nothing else holds.
+    pub fn get_mut(&mut self, id: BufferId) -> &mut Arc<IndexedString> {
+        return &mut self.buffers[id.0];
+    }
+
+    // Registering the same buffer twice returns the existing ID.
+    pub fn register(&mut self, buffer: &Arc<IndexedString>) -> BufferId {
+        let known = self.buffers.iter().position(|own| Arc::ptr_eq(own, buffer));
+
+        return BufferId(known.unwrap_or_else(|| {
+            self.buffers.push(Arc::clone(buffer));
+            self.buffers.len() - 1
+        }));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.buffers.len();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BufferId, &Arc<IndexedString>)> {
+        return self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| (BufferId(index), buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let mut registry = BufferRegistry::new();
+        let first = Arc::new(IndexedString::from("first"));
+        let second = Arc::new(IndexedString::from("second"));
+
+        assert_eq!(registry.register(&first), BufferId(0));
+        assert_eq!(registry.register(&second), BufferId(1));
+        assert_eq!(registry.get(BufferId(1)).to_string(), "second");
+    }
+
+    #[test]
+    fn test_register_deduplicates() {
+        let mut registry = BufferRegistry::new();
+        let buffer = Arc::new(IndexedString::from("text"));
+        let lookalike = Arc::new(IndexedString::from("text"));
+
+        assert_eq!(registry.register(&buffer), registry.register(&buffer));
+        assert_ne!(registry.register(&buffer), registry.register(&lookalike));
+        assert_eq!(registry.len(), 2);
+    }
+}
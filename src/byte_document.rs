@@ -0,0 +1,297 @@
+use super::buffers::BufferId;
+use super::changes::Change;
+use super::fragment::{Fragment, Source};
+use super::fragment_map::FragmentMap;
+use super::invariants::{check_fragments, InvariantViolation};
+use super::Deleted;
+use std::io::{self, Write};
+use std::ops::Range;
+
+// A piece table over raw bytes, for binary files and hex editors. Uses the
+// same fragment map as `Document` but has no notion of characters or lines,
+// so any byte offset is a valid edit position.
+#[derive(Clone)]
+pub struct ByteDocument {
+    fragments: FragmentMap,
+    original: Vec<u8>,
+    insertions: Vec<u8>,
+    // Bytes handed over whole by `include`.
+    buffers: Vec<Vec<u8>>,
+}
+
+impl ByteDocument {
+    pub fn from(bytes: &[u8]) -> Self {
+        return ByteDocument::from_vec(bytes.to_vec());
+    }
+
+    // Takes ownership of the bytes without copying them.
+    pub fn from_vec(original: Vec<u8>) -> Self {
+        ByteDocument {
+            fragments: FragmentMap::new(Fragment::new(Source::Original, 0, original.len())),
+            original,
+            insertions: vec![],
+            buffers: vec![],
+        }
+    }
+
+    pub fn new() -> Self {
+        return ByteDocument::from_vec(vec![]);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.fragments.byte_length();
+    }
+
+    pub fn insert(&mut self, byte_offset: usize, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        let frag = self.create_insertion_fragment(bytes);
+        self.fragments.insert_fragment(byte_offset, frag);
+        self.after_edit();
+    }
+
+    // Inserts bytes by taking ownership of them, so large blocks aren't
+    // copied into the insertions buffer.
+    pub fn include(&mut self, byte_offset: usize, bytes: Vec<u8>) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        let id = BufferId::from_index(self.buffers.len());
+        let frag = Fragment::new(Source::Buffer(id), 0, bytes.len());
+        self.buffers.push(bytes);
+
+        self.splice(&(byte_offset..byte_offset), vec![frag]);
+        self.after_edit();
+    }
+
+    // The removed bytes stay in the buffers; see `Deleted::slices`.
+    pub fn delete(&mut self, range: &Range<usize>) -> Deleted {
+        return self.replace(range, &[]);
+    }
+
+    // Deletes a range and inserts bytes in its place as a single edit.
+    pub fn replace(&mut self, range: &Range<usize>, bytes: &[u8]) -> Deleted {
+        let pieces = match bytes.is_empty() {
+            true => vec![],
+            false => vec![self.create_insertion_fragment(bytes)],
+        };
+
+        let deleted = self.splice(range, pieces);
+        self.after_edit();
+
+        return deleted;
+    }
+
+    // Puts previously deleted bytes back without copying them. The
+    // `Deleted` value must have come from this document.
+    pub fn reinsert(&mut self, byte_offset: usize, deleted: &Deleted) {
+        self.splice(&(byte_offset..byte_offset), deleted.fragments.clone());
+        self.after_edit();
+    }
+
+    // Hex-editor overtype: replaces one byte per byte written, growing the
+    // document if the write runs past the end.
+    pub fn overwrite(&mut self, byte_offset: usize, bytes: &[u8]) -> Deleted {
+        let start = byte_offset.min(self.len());
+
+        return self.replace(&(start..start + bytes.len()), bytes);
+    }
+
+    // Borrows the bytes in a range one fragment at a time.
+    pub fn slices(&self, range: &Range<usize>) -> impl Iterator<Item = &[u8]> + '_ {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+
+        return self
+            .fragments
+            .slice_fragments(&(start..end))
+            .into_iter()
+            .map(move |frag| self.fragment_bytes(&frag));
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        self.slices(&(0..self.len()))
+            .for_each(|slice| bytes.extend_from_slice(slice));
+
+        return bytes;
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for slice in self.slices(&(0..self.len())) {
+            writer.write_all(slice)?;
+        }
+
+        return Ok(());
+    }
+
+    // Counts edits, like `Document::version`.
+    pub fn version(&self) -> u64 {
+        return self.fragments.changes().version();
+    }
+
+    // The edits made since `version`, like `Document::changes_since`.
+    pub fn changes_since(&self, version: u64) -> Option<Vec<Change>> {
+        return self.fragments.changes().since(version);
+    }
+
+    // Verifies the fragment map is a gapless, in-bounds cover of the bytes.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        return check_fragments(&self.fragments, |source| self.get_buffer(source).len());
+    }
+
+    // Runs after every public edit, like `Document::after_edit`.
+    fn after_edit(&self) {
+        #[cfg(all(debug_assertions, feature = "check-invariants"))]
+        {
+            if let Err(violation) = self.check_invariants() {
+                panic!("Piece table invariant violated: {}", violation);
+            }
+        }
+    }
+
+    fn splice(&mut self, range: &Range<usize>, pieces: Vec<Fragment>) -> Deleted {
+        let start = range.start.min(self.len());
+        let range = start..range.end.max(start).min(self.len());
+        let removed = self.fragments.splice(&range, pieces);
+
+        return Deleted::new(range.start, removed);
+    }
+
+    pub(crate) fn fragment_bytes(&self, frag: &Fragment) -> &[u8] {
+        let buffer = self.get_buffer(&frag.source);
+
+        return &buffer[frag.byte_offset..frag.byte_offset + frag.byte_length];
+    }
+
+    fn get_buffer(&self, source: &Source) -> &[u8] {
+        return match source {
+            Source::Insertion => &self.insertions,
+            Source::Original => &self.original,
+            Source::Buffer(id) => &self.buffers[id.index()],
+        };
+    }
+
+    fn create_insertion_fragment(&mut self, bytes: &[u8]) -> Fragment {
+        let offset = self.insertions.len();
+        self.insertions.extend_from_slice(bytes);
+
+        return Fragment::of_insertion(offset, bytes.len());
+    }
+}
+
+impl Default for ByteDocument {
+    fn default() -> Self {
+        return ByteDocument::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deleted_bytes(deleted: &Deleted, document: &ByteDocument) -> Vec<u8> {
+        return deleted.slices(document).flatten().copied().collect();
+    }
+
+    #[test]
+    fn test_construction() {
+        let bytes = ByteDocument::from(&[0x00, 0xFF, 0x10]);
+
+        assert_eq!(bytes.len(), 3);
+        assert_eq!(bytes.to_vec(), vec![0x00, 0xFF, 0x10]);
+        assert!(ByteDocument::new().is_empty());
+    }
+
+    #[test]
+    fn test_insert_splits_anywhere() {
+        // Not valid UTF-8, and the insert lands mid-"sequence".
+        let mut bytes = ByteDocument::from(&[0xE2, 0x80, 0xA8]);
+        bytes.insert(1, &[0xFF]);
+        bytes.insert(10, &[0x01]);
+
+        assert_eq!(bytes.to_vec(), vec![0xE2, 0xFF, 0x80, 0xA8, 0x01]);
+    }
+
+    #[test]
+    fn test_delete_across_fragments() {
+        let mut bytes = ByteDocument::from(&[1, 2, 3, 4]);
+        bytes.insert(2, &[9, 9]);
+
+        let deleted = bytes.delete(&(1..5));
+        assert_eq!(deleted_bytes(&deleted, &bytes), vec![2, 9, 9, 3]);
+        assert_eq!(bytes.to_vec(), vec![1, 4]);
+
+        bytes.reinsert(1, &deleted);
+        assert_eq!(bytes.to_vec(), vec![1, 2, 9, 9, 3, 4]);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut bytes = ByteDocument::from(&[1, 2, 3, 4]);
+
+        let version = bytes.version();
+        let deleted = bytes.replace(&(1..3), &[7]);
+
+        assert_eq!(deleted_bytes(&deleted, &bytes), vec![2, 3]);
+        assert_eq!(bytes.to_vec(), vec![1, 7, 4]);
+        assert_eq!(
+            bytes.changes_since(version),
+            Some(vec![Change {
+                start: 1,
+                deleted: 2,
+                inserted: 1
+            }])
+        );
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let mut bytes = ByteDocument::from(&[1, 2, 3, 4]);
+
+        let deleted = bytes.overwrite(1, &[8, 8]);
+        assert_eq!(deleted_bytes(&deleted, &bytes), vec![2, 3]);
+        assert_eq!(bytes.to_vec(), vec![1, 8, 8, 4]);
+
+        let deleted = bytes.overwrite(3, &[5, 6, 7]);
+        assert_eq!(deleted_bytes(&deleted, &bytes), vec![4]);
+        assert_eq!(bytes.to_vec(), vec![1, 8, 8, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_include() {
+        let mut bytes = ByteDocument::from(&[1, 2]);
+        bytes.include(1, vec![5, 6, 7]);
+        let deleted = bytes.delete(&(0..3));
+
+        assert_eq!(bytes.to_vec(), vec![7, 2]);
+        assert_eq!(deleted_bytes(&deleted, &bytes), vec![1, 5, 6]);
+        assert_eq!(bytes.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_slices_borrow_fragments() {
+        let mut bytes = ByteDocument::from(&[1, 2, 3]);
+        bytes.insert(1, &[0]);
+        let slices: Vec<&[u8]> = bytes.slices(&(0..3)).collect();
+
+        assert_eq!(slices, vec![&[1][..], &[0][..], &[2][..]]);
+    }
+
+    #[test]
+    fn test_write_to() {
+        let mut bytes = ByteDocument::from(&[1, 2]);
+        bytes.insert(2, &[3]);
+        let mut output = vec![];
+        bytes.write_to(&mut output).unwrap();
+
+        assert_eq!(output, vec![1, 2, 3]);
+    }
+}
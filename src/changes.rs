@@ -0,0 +1,173 @@
+use super::Document;
+use std::collections::VecDeque;
+
+// How many recent edits are remembered for `Document::changes_since`.
+const HISTORY: usize = 1024;
+
+// An edit as seen from outside the fragment map: `deleted` bytes at `start`
+// were replaced by `inserted` bytes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Change {
+    pub start: usize,
+    pub deleted: usize,
+    pub inserted: usize,
+}
+
+// The most recent edits, numbered so observers can catch up on what they
+// missed.
+#[derive(Clone)]
+pub struct ChangeLog {
+    version: u64,
+    recent: VecDeque<Change>,
+}
+
+impl Change {
+    // Where an offset ends up after this change. Offsets inside the
+    // deleted range collapse to its start.
+    pub fn map_offset(&self, offset: usize) -> usize {
+        if offset <= self.start {
+            return offset;
+        }
+
+        if offset < self.start + self.deleted {
+            return self.start;
+        }
+
+        return offset + self.inserted - self.deleted;
+    }
+}
+
+impl ChangeLog {
+    pub fn new() -> Self {
+        ChangeLog {
+            version: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    // A log at `version` that remembers `recent`, the edits leading up to
+    // it, or as many as fit.
+    pub fn restore(version: u64, recent: Vec<Change>) -> Self {
+        let skip = recent.len().saturating_sub(HISTORY);
+
+        return ChangeLog {
+            version,
+            recent: recent.into_iter().skip(skip).collect(),
+        };
+    }
+
+    pub fn record(&mut self, change: Change) {
+        if change.deleted == 0 && change.inserted == 0 {
+            return;
+        }
+
+        if self.recent.len() == HISTORY {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(change);
+        self.version += 1;
+    }
+
+    pub fn version(&self) -> u64 {
+        return self.version;
+    }
+
+    // The edits made since `version`, oldest first, or `None` if they're no
+    // longer remembered.
+    pub fn since(&self, version: u64) -> Option<Vec<Change>> {
+        let missed = self.version.checked_sub(version)? as usize;
+
+        if missed > self.recent.len() {
+            return None;
+        }
+
+        return Some(
+            self.recent
+                .iter()
+                .skip(self.recent.len() - missed)
+                .copied()
+                .collect(),
+        );
+    }
+}
+
+impl Document {
+    // Counts edits. Goes up by one for every edit that changes the text.
+    pub fn version(&self) -> u64 {
+        return self.fragments.changes().version();
+    }
+
+    // The edits made since `version`, oldest first, each in terms of the
+    // text as it was right before it. `None` if it's been too long.
+    pub fn changes_since(&self, version: u64) -> Option<Vec<Change>> {
+        return self.fragments.changes().since(version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edits_are_recorded() {
+        let mut text = Document::from("hello world");
+        let version = text.version();
+
+        text.insert(5, ",");
+        text.delete(&(0..1));
+        text.replace(&(0..4), "J");
+        text.insert(3, "");
+
+        assert_eq!(text.version(), version + 3);
+        assert_eq!(
+            text.changes_since(version),
+            Some(vec![
+                Change {
+                    start: 5,
+                    deleted: 0,
+                    inserted: 1
+                },
+                Change {
+                    start: 0,
+                    deleted: 1,
+                    inserted: 0
+                },
+                Change {
+                    start: 0,
+                    deleted: 4,
+                    inserted: 1
+                },
+            ])
+        );
+        assert_eq!(text.changes_since(text.version()), Some(vec![]));
+    }
+
+    #[test]
+    fn test_old_versions_are_forgotten() {
+        let mut text = Document::new();
+
+        for _ in 0..HISTORY + 1 {
+            text.insert(text.len(), "x");
+        }
+
+        assert_eq!(text.changes_since(0), None);
+        assert_eq!(
+            text.changes_since(1).map(|changes| changes.len()),
+            Some(HISTORY)
+        );
+    }
+
+    #[test]
+    fn test_map_offset() {
+        let change = Change {
+            start: 2,
+            deleted: 3,
+            inserted: 1,
+        };
+
+        assert_eq!(change.map_offset(2), 2);
+        assert_eq!(change.map_offset(4), 2);
+        assert_eq!(change.map_offset(5), 3);
+    }
+}
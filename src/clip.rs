@@ -0,0 +1,160 @@
+use super::buffers::BufferRegistry;
+use super::fragment::{Fragment, Source};
+use super::Document;
+use std::{fmt, ops::Range};
+
+// A range of text detached from any document, e.g. clipboard contents. It
+// holds references to the buffers the text lives in, so copying and pasting
+// clips between documents never copies text bytes.
+#[derive(Clone, Default)]
+pub struct Clip {
+    pub(crate) buffers: BufferRegistry,
+    // Every fragment's source is a `Source::Buffer` in `buffers`.
+    pub(crate) fragments: Vec<Fragment>,
+}
+
+impl Clip {
+    pub fn len(&self) -> usize {
+        return self.fragments.iter().map(|frag| frag.byte_length).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+impl fmt::Display for Clip {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for frag in self.fragments.iter() {
+            if let Source::Buffer(id) = frag.source {
+                write!(fmt, "{}", frag.get_slice(self.buffers.get(id)))?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl Document {
+    // Captures a range by reference. Text typed afterwards goes to a new
+    // buffer, so the one the clip holds is never copied.
+    pub fn copy(&self, range: &Range<usize>) -> Clip {
+        let mut clip = Clip::default();
+        let range = range.start.min(self.len())..range.end.min(self.len());
+
+        for mut frag in self.fragments.slice_fragments(&range) {
+            frag.source = Source::Buffer(clip.buffers.register(self.get_buffer(&frag.source)));
+            clip.fragments.push(frag);
+        }
+
+        return clip;
+    }
+
+    // Inserts a clip, possibly from another document, without copying its
+    // text into the insertion buffer.
+    pub fn paste(&mut self, byte_offset: usize, clip: &Clip) {
+        let pieces = clip
+            .fragments
+            .iter()
+            .map(|frag| {
+                let buffer = match frag.source {
+                    Source::Buffer(id) => clip.buffers.get(id),
+                    _ => unreachable!("Clip fragments always refer to clip buffers"),
+                };
+
+                let source = self.adopt_buffer(buffer);
+                Fragment::new(source, frag.byte_offset, frag.byte_length)
+            })
+            .collect();
+
+        self.splice(&(byte_offset..byte_offset), pieces);
+        self.after_edit();
+    }
+
+    // Shorthand for pasting a copy of another document's range.
+    pub fn paste_from(&mut self, byte_offset: usize, other: &Document, range: &Range<usize>) {
+        self.paste(byte_offset, &other.copy(range));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffers::BufferId;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_copy() {
+        let mut text = Document::from("original");
+        text.insert(8, " text");
+        let clip = text.copy(&(4..11));
+
+        assert_eq!(clip.to_string(), "inal te");
+        assert_eq!(clip.len(), 7);
+        assert_eq!(clip.buffers.len(), 2);
+    }
+
+    #[test]
+    fn test_paste_between_documents() {
+        let mut source = Document::from("shared words");
+        source.insert(0, "some ");
+        let mut target = Document::from("[]");
+        target.paste_from(1, &source, &(0..11));
+
+        assert_eq!(target.to_string(), "[some shared]");
+        assert_eq!(target.insertions.len(), 0);
+        assert_eq!(target.buffers.len(), 2);
+    }
+
+    #[test]
+    fn test_paste_back_into_source() {
+        let mut text = Document::from("abc");
+        let clip = text.copy(&(0..2));
+        text.paste(3, &clip);
+
+        assert_eq!(text.to_string(), "abcab");
+        assert_eq!(text.buffers.len(), 0);
+    }
+
+    #[test]
+    fn test_source_edits_dont_affect_clip() {
+        let mut text = Document::new();
+        text.insert(0, "typed");
+        let clip = text.copy(&(0..5));
+        text.insert(5, " more");
+
+        assert_eq!(clip.to_string(), "typed");
+        assert_eq!(text.to_string(), "typed more");
+    }
+
+    #[test]
+    fn test_typing_after_copy_doesnt_copy_the_buffer() {
+        let mut text = Document::new();
+        text.insert(0, "typed");
+        let insertions = Arc::clone(&text.insertions);
+        let clip = text.copy(&(0..5));
+        text.insert(5, " more");
+        text.insert(10, "!");
+
+        // The clip and the document still share the one buffer, and the
+        // new text went to a buffer of its own.
+        assert!(Arc::ptr_eq(
+            clip.buffers.get(BufferId::from_index(0)),
+            &insertions
+        ));
+        assert!(Arc::ptr_eq(&text.insertions, &insertions));
+        assert_eq!(text.insertions.to_string(), "typed");
+        assert_eq!(text.buffers.len(), 1);
+        assert_eq!(
+            text.buffers.get(BufferId::from_index(0)).to_string(),
+            " more!"
+        );
+
+        let again = text.copy(&(0..text.len()));
+        text.insert(0, ">");
+        text.paste(1, &again);
+        assert_eq!(text.to_string(), ">typed more!typed more!");
+        assert_eq!(text.buffers.len(), 2);
+        assert_eq!(text.check_invariants(), Ok(()));
+    }
+}
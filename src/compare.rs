@@ -0,0 +1,213 @@
+use super::diff::{diff_chars, diff_lines, diff_pieces, push_hunk, Hunk};
+use super::modified::normalize;
+use super::Document;
+use std::ops::Range;
+use std::sync::Arc;
+
+// How finely `Document::diff` reports differences.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Granularity {
+    // Hunks cover whole lines, as in a unified diff.
+    Line,
+    // Hunks cover only the characters that differ.
+    Char,
+}
+
+impl Document {
+    // Byte range hunks turning this document's text into `other`'s. Text
+    // both documents take from the same place in the same buffer, e.g.
+    // after copying and pasting between them, is matched without being
+    // read, so only the pieces that differ are compared as text.
+    pub fn diff(&self, other: &Document, granularity: Granularity) -> Vec<Hunk> {
+        let mut regions = diff_pieces(&self.shared_pieces(), &other.shared_pieces());
+
+        if granularity == Granularity::Line {
+            regions = widen_to_lines(self, other, &regions);
+        }
+
+        let mut hunks = vec![];
+
+        for region in regions {
+            let old: String = self.chunks(&region.old).collect();
+            let new: String = other.chunks(&region.new).collect();
+
+            let inner = match granularity {
+                Granularity::Line => diff_lines(&old, &new),
+                Granularity::Char => diff_chars(&old, &new),
+            };
+
+            // Hunks from neighbouring regions may touch; `push_hunk` joins them.
+            for hunk in inner {
+                push_hunk(
+                    &mut hunks,
+                    region.old.start + hunk.old.start..region.old.start + hunk.old.end,
+                    region.new.start + hunk.new.start..region.new.start + hunk.new.end,
+                );
+            }
+        }
+
+        return hunks;
+    }
+
+    // Pieces keyed by the address of their buffer, which documents sharing
+    // a buffer agree on, unlike their buffer ids.
+    fn shared_pieces(&self) -> Vec<(usize, Range<usize>)> {
+        return normalize(self.fragments.values().cloned().collect())
+            .iter()
+            .map(|frag| {
+                let buffer = Arc::as_ptr(self.get_buffer(&frag.source)) as usize;
+                (
+                    buffer,
+                    frag.byte_offset..frag.byte_offset + frag.byte_length,
+                )
+            })
+            .collect();
+    }
+}
+
+fn is_line_start(document: &Document, at: usize) -> bool {
+    return at == 0 || document.rfind("\n", at).map(|found| found.end) == Some(at);
+}
+
+// Widens hunks to whole lines so each can be diffed line by line, joining
+// those that end up sharing a line. The text between two hunks is the same
+// in both documents, so a hunk widens by the same number of bytes on each.
+fn widen_to_lines(old: &Document, new: &Document, hunks: &[Hunk]) -> Vec<Hunk> {
+    let mut widened: Vec<Hunk> = vec![];
+
+    for hunk in hunks {
+        let line_start = old.rfind("\n", hunk.old.start).map_or(0, |found| found.end);
+        let mut old_range = line_start..hunk.old.end;
+        let mut new_range = match widened.last() {
+            // This hunk starts on the line the last one ended on, so the text
+            // before it isn't the same on both sides; join the two.
+            Some(last) if line_start < last.old.end => {
+                old_range.start = last.old.start;
+                widened.pop().unwrap().new.start..hunk.new.end
+            }
+            _ => hunk.new.start - (hunk.old.start - line_start)..hunk.new.end,
+        };
+
+        if !is_line_start(old, old_range.end) || !is_line_start(new, new_range.end) {
+            let line_end = old
+                .find("\n", old_range.end)
+                .map_or(old.len(), |found| found.end);
+            new_range.end += line_end - old_range.end;
+            old_range.end = line_end;
+        }
+
+        widened.push(Hunk {
+            old: old_range,
+            new: new_range,
+        });
+    }
+
+    return widened;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_unrelated_documents() {
+        let old = Document::from("one\ntwo\nthree\n");
+        let new = Document::from("one\n2\nthree\nfour\n");
+
+        assert_eq!(
+            old.diff(&new, Granularity::Line),
+            vec![
+                Hunk {
+                    old: 4..8,
+                    new: 4..6
+                },
+                Hunk {
+                    old: 14..14,
+                    new: 12..17
+                },
+            ]
+        );
+        assert_eq!(
+            old.diff(&new, Granularity::Char),
+            vec![
+                Hunk {
+                    old: 4..7,
+                    new: 4..5
+                },
+                Hunk {
+                    old: 13..13,
+                    new: 11..16
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_identical_documents() {
+        let mut text = Document::from("same text");
+        text.insert(4, " old");
+        text.delete(&(4..8));
+
+        assert!(text.diff(&text, Granularity::Char).is_empty());
+        assert!(text
+            .diff(&Document::from("same text"), Granularity::Line)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_diff_shared_buffers() {
+        let original = Document::from("alpha\nbeta\ngamma\ndelta\n");
+        let mut edited = Document::new();
+        edited.paste(0, &original.copy(&(0..original.len())));
+        edited.replace(&(11..16), "GAMMA");
+        edited.insert(0, "zero\n");
+
+        // Only the edited pieces and the lines around them are read.
+        assert_eq!(original.shared_pieces()[0].0, edited.shared_pieces()[1].0);
+        assert_eq!(
+            original.diff(&edited, Granularity::Line),
+            vec![
+                Hunk {
+                    old: 0..0,
+                    new: 0..5
+                },
+                Hunk {
+                    old: 11..17,
+                    new: 16..22
+                },
+            ]
+        );
+        assert_eq!(
+            original.diff(&edited, Granularity::Char),
+            vec![
+                Hunk {
+                    old: 0..0,
+                    new: 0..5
+                },
+                Hunk {
+                    old: 11..16,
+                    new: 16..21
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_edits_within_a_line() {
+        let original = Document::from("one two three\nfour");
+        let mut edited = Document::new();
+        edited.paste(0, &original.copy(&(0..original.len())));
+        edited.replace(&(0..3), "1");
+        edited.replace(&(6..11), "3");
+        edited.insert(edited.len(), "!");
+
+        assert_eq!(
+            original.diff(&edited, Granularity::Line),
+            vec![Hunk {
+                old: 0..18,
+                new: 0..13
+            }]
+        );
+        assert_eq!(original.diff(&edited, Granularity::Char).len(), 3);
+    }
+}
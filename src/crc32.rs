@@ -0,0 +1,83 @@
+// CRC-32 as used by zip, PNG and Ethernet (reflected, polynomial
+// 0xEDB88320), for spotting damaged files.
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            value = match value & 1 {
+                1 => (value >> 1) ^ POLYNOMIAL,
+                _ => value >> 1,
+            };
+            bit += 1;
+        }
+
+        table[index] = value;
+        index += 1;
+    }
+
+    return table;
+}
+
+// A checksum that can be fed its input a piece at a time.
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let index = (self.state ^ *byte as u32) & 0xFF;
+            self.state = (self.state >> 8) ^ TABLE[index as usize];
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        return !self.state;
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+
+    return crc.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn test_pieces() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+
+        assert_eq!(crc.finish(), crc32(b"123456789"));
+    }
+}
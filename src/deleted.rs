@@ -0,0 +1,99 @@
+use super::fragment::Fragment;
+use super::{ByteDocument, Document};
+use std::fmt;
+
+// Text removed from a document, or bytes from a `ByteDocument`. The bytes
+// still live in the document's buffers, so holding onto it is cheap and
+// putting it back copies nothing.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Deleted {
+    pub(crate) start: usize,
+    pub(crate) fragments: Vec<Fragment>,
+}
+
+// Renders deleted text on demand. See `Deleted::text`.
+pub struct DeletedText<'a> {
+    deleted: &'a Deleted,
+    document: &'a Document,
+}
+
+impl Deleted {
+    pub(crate) fn new(start: usize, fragments: Vec<Fragment>) -> Self {
+        Deleted { start, fragments }
+    }
+
+    // Byte offset the text was deleted from.
+    pub fn start(&self) -> usize {
+        return self.start;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.fragments.iter().map(|frag| frag.byte_length).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    // Nothing is copied until the result is displayed. The document must be
+    // the one the text was deleted from.
+    pub fn text<'a>(&'a self, document: &'a Document) -> DeletedText<'a> {
+        return DeletedText {
+            deleted: self,
+            document,
+        };
+    }
+
+    // Borrows the deleted bytes one fragment at a time. The document must
+    // be the one they were deleted from.
+    pub fn slices<'a>(&'a self, document: &'a ByteDocument) -> impl Iterator<Item = &'a [u8]> + 'a {
+        return self
+            .fragments
+            .iter()
+            .map(move |frag| document.fragment_bytes(frag));
+    }
+}
+
+impl<'a> fmt::Display for DeletedText<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for frag in self.deleted.fragments.iter() {
+            let source = self.document.get_fragment_source(frag);
+            write!(fmt, "{}", frag.get_slice(source))?;
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_deletion() {
+        let mut text = Document::from("text");
+        let deleted = text.delete(&(2..2));
+
+        assert!(deleted.is_empty());
+        assert_eq!(deleted.text(&text).to_string(), "");
+        assert_eq!(text.to_string(), "text");
+    }
+
+    #[test]
+    fn test_text_survives_later_edits() {
+        let mut text = Document::from("hello world");
+        let deleted = text.delete(&(5..11));
+        text.insert(5, ", friend");
+
+        assert_eq!(deleted.text(&text).to_string(), " world");
+    }
+
+    #[test]
+    fn test_deletion_past_the_end() {
+        let mut text = Document::from("text");
+        let deleted = text.delete(&(2..10));
+
+        assert_eq!(deleted.len(), 2);
+        assert_eq!(text.to_string(), "te");
+    }
+}
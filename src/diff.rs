@@ -0,0 +1,462 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+// Changed lines bigger than this together aren't refined further.
+const REFINE_LIMIT: usize = 64 * 1024;
+
+// A run of differences between two sequences: `old` in the first was
+// replaced by `new` in the second. Either side may be empty.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+// The shortest edit script turning `old` into `new`, as hunks of element
+// indices in order. Uses Myers' algorithm with the linear space
+// refinement, so memory doesn't grow with the number of differences.
+pub fn diff_slices<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Hunk> {
+    let mut hunks = vec![];
+    diff_ranges(old, new, 0..old.len(), 0..new.len(), &mut hunks);
+
+    return hunks;
+}
+
+fn diff_ranges<T: PartialEq>(
+    old: &[T],
+    new: &[T],
+    mut a: Range<usize>,
+    mut b: Range<usize>,
+    hunks: &mut Vec<Hunk>,
+) {
+    while !a.is_empty() && !b.is_empty() && old[a.start] == new[b.start] {
+        a.start += 1;
+        b.start += 1;
+    }
+
+    while !a.is_empty() && !b.is_empty() && old[a.end - 1] == new[b.end - 1] {
+        a.end -= 1;
+        b.end -= 1;
+    }
+
+    if a.is_empty() && b.is_empty() {
+        return;
+    }
+
+    if a.is_empty() || b.is_empty() {
+        return push_hunk(hunks, a, b);
+    }
+
+    match middle_snake(&old[a.clone()], &new[b.clone()]) {
+        Some((x, y)) => {
+            diff_ranges(old, new, a.start..a.start + x, b.start..b.start + y, hunks);
+            diff_ranges(old, new, a.start + x..a.end, b.start + y..b.end, hunks);
+        }
+        None => push_hunk(hunks, a, b),
+    }
+}
+
+// Byte range hunks between two texts: changed lines are found first, then
+// narrowed down to the characters that differ within them.
+pub fn diff_text(old: &str, new: &str) -> Vec<Hunk> {
+    let mut hunks = vec![];
+
+    for hunk in diff_lines(old, new) {
+        if hunk.old.len() + hunk.new.len() > REFINE_LIMIT {
+            hunks.push(hunk);
+            continue;
+        }
+
+        for inner in diff_chars(&old[hunk.old.clone()], &new[hunk.new.clone()]) {
+            hunks.push(Hunk {
+                old: hunk.old.start + inner.old.start..hunk.old.start + inner.old.end,
+                new: hunk.new.start + inner.new.start..hunk.new.start + inner.new.end,
+            });
+        }
+    }
+
+    return hunks;
+}
+
+// Byte range hunks covering whole lines.
+pub fn diff_lines(old: &str, new: &str) -> Vec<Hunk> {
+    let (old_lines, old_offsets) = split_units(old, old.split_inclusive('\n'));
+    let (new_lines, new_offsets) = split_units(new, new.split_inclusive('\n'));
+
+    return to_bytes(
+        diff_slices(&old_lines, &new_lines),
+        &old_offsets,
+        &new_offsets,
+    );
+}
+
+// Byte range hunks covering whole characters.
+pub fn diff_chars(old: &str, new: &str) -> Vec<Hunk> {
+    let (old_chars, old_offsets) = split_units(old, old.chars());
+    let (new_chars, new_offsets) = split_units(new, new.chars());
+
+    return to_bytes(
+        diff_slices(&old_chars, &new_chars),
+        &old_offsets,
+        &new_offsets,
+    );
+}
+
+trait Unit {
+    fn byte_length(&self) -> usize;
+}
+
+impl Unit for char {
+    fn byte_length(&self) -> usize {
+        return self.len_utf8();
+    }
+}
+
+impl Unit for &str {
+    fn byte_length(&self) -> usize {
+        return self.len();
+    }
+}
+
+// Collects the units of a text along with where each one starts. The
+// offsets end with the text's length.
+fn split_units<T: Unit>(text: &str, units: impl Iterator<Item = T>) -> (Vec<T>, Vec<usize>) {
+    let mut offsets = vec![0];
+    let units: Vec<T> = units
+        .inspect(|unit| offsets.push(offsets[offsets.len() - 1] + unit.byte_length()))
+        .collect();
+
+    debug_assert_eq!(offsets[offsets.len() - 1], text.len());
+
+    return (units, offsets);
+}
+
+fn to_bytes(hunks: Vec<Hunk>, old_offsets: &[usize], new_offsets: &[usize]) -> Vec<Hunk> {
+    return hunks
+        .into_iter()
+        .map(|hunk| Hunk {
+            old: old_offsets[hunk.old.start]..old_offsets[hunk.old.end],
+            new: new_offsets[hunk.new.start]..new_offsets[hunk.new.end],
+        })
+        .collect();
+}
+
+// Byte range hunks between two texts described as pieces of buffers, each
+// a buffer key and a byte range in that buffer. Pieces are compared by
+// where their bytes come from, not by the bytes themselves, so this costs
+// nothing like a text diff, but text that was retyped counts as different.
+pub fn diff_pieces<K: Ord + Clone>(
+    old: &[(K, Range<usize>)],
+    new: &[(K, Range<usize>)],
+) -> Vec<Hunk> {
+    // Cut every piece wherever a piece on either side starts or ends in the
+    // same buffer, so any two atoms are either identical or disjoint.
+    let mut cuts: BTreeMap<&K, BTreeSet<usize>> = BTreeMap::new();
+
+    for (key, range) in old.iter().chain(new) {
+        let points = cuts.entry(key).or_default();
+        points.insert(range.start);
+        points.insert(range.end);
+    }
+
+    let atomize = |pieces: &[(K, Range<usize>)]| {
+        let mut atoms = vec![];
+        let mut offsets = vec![0];
+
+        for (key, range) in pieces {
+            let mut start = range.start;
+
+            for point in cuts[key].range(range.start + 1..range.end + 1) {
+                atoms.push((key.clone(), start..*point));
+                offsets.push(offsets[offsets.len() - 1] + point - start);
+                start = *point;
+            }
+        }
+
+        (atoms, offsets)
+    };
+
+    let (old_atoms, old_offsets) = atomize(old);
+    let (new_atoms, new_offsets) = atomize(new);
+
+    return to_bytes(
+        diff_slices(&old_atoms, &new_atoms),
+        &old_offsets,
+        &new_offsets,
+    );
+}
+
+// Joins hunks that touch, since recursion can split one run of changes.
+pub fn push_hunk(hunks: &mut Vec<Hunk>, old: Range<usize>, new: Range<usize>) {
+    if let Some(last) = hunks.last_mut() {
+        if last.old.end == old.start && last.new.end == new.start {
+            last.old.end = old.end;
+            last.new.end = new.end;
+            return;
+        }
+    }
+
+    hunks.push(Hunk { old, new });
+}
+
+// Searches forwards from the start and backwards from the end at once
+// until the two meet on an optimal path, and returns a point on it to split
+// the problem at. `None` if the sequences have nothing in common.
+fn middle_snake<T: PartialEq>(old: &[T], new: &[T]) -> Option<(usize, usize)> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d;
+    let length = 2 * max_d + 2;
+
+    // Furthest x reached on each diagonal k = x - y, going forwards and
+    // (measured from the ends) backwards.
+    let mut forward = vec![-1; length as usize];
+    let mut backward = vec![-1; length as usize];
+    forward[(offset + 1) as usize] = 0;
+    backward[(offset + 1) as usize] = 0;
+
+    let delta = n - m;
+    // With an odd delta the forward search is the one that meets the
+    // backward paths; with an even one, the reverse.
+    let check_forward = delta % 2 != 0;
+
+    // Diagonals that ran off the edges aren't worth extending.
+    let (mut k1_start, mut k1_end, mut k2_start, mut k2_end) = (0, 0, 0, 0);
+
+    for d in 0..max_d {
+        let mut k1 = -d + k1_start;
+
+        while k1 <= d - k1_end {
+            let index = (offset + k1) as usize;
+            let mut x1 = match k1 == -d || (k1 != d && forward[index - 1] < forward[index + 1]) {
+                true => forward[index + 1],
+                false => forward[index - 1] + 1,
+            };
+            let mut y1 = x1 - k1;
+
+            while x1 < n && y1 < m && old[x1 as usize] == new[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+
+            forward[index] = x1;
+
+            if x1 > n {
+                k1_end += 2;
+            } else if y1 > m {
+                k1_start += 2;
+            } else if check_forward {
+                let other = offset + delta - k1;
+
+                if other >= 0 && other < length && backward[other as usize] != -1 {
+                    let x2 = n - backward[other as usize];
+
+                    if x1 >= x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+
+            k1 += 2;
+        }
+
+        let mut k2 = -d + k2_start;
+
+        while k2 <= d - k2_end {
+            let index = (offset + k2) as usize;
+            let mut x2 = match k2 == -d || (k2 != d && backward[index - 1] < backward[index + 1]) {
+                true => backward[index + 1],
+                false => backward[index - 1] + 1,
+            };
+            let mut y2 = x2 - k2;
+
+            while x2 < n && y2 < m && old[(n - x2 - 1) as usize] == new[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+
+            backward[index] = x2;
+
+            if x2 > n {
+                k2_end += 2;
+            } else if y2 > m {
+                k2_start += 2;
+            } else if !check_forward {
+                let other = offset + delta - k2;
+
+                if other >= 0 && other < length && forward[other as usize] != -1 {
+                    let x1 = forward[other as usize];
+                    let y1 = offset + x1 - other;
+
+                    if x1 >= n - x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+
+            k2 += 2;
+        }
+    }
+
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Applies hunks to `old` using the elements of `new`.
+    fn patch(old: &[char], new: &[char], hunks: &[Hunk]) -> Vec<char> {
+        let mut result = vec![];
+        let mut cursor = 0;
+
+        for hunk in hunks {
+            result.extend_from_slice(&old[cursor..hunk.old.start]);
+            result.extend_from_slice(&new[hunk.new.clone()]);
+            cursor = hunk.old.end;
+        }
+
+        result.extend_from_slice(&old[cursor..]);
+
+        return result;
+    }
+
+    fn cost(hunks: &[Hunk]) -> usize {
+        return hunks
+            .iter()
+            .map(|hunk| hunk.old.len() + hunk.new.len())
+            .sum();
+    }
+
+    #[test]
+    fn test_diff() {
+        let old: Vec<char> = "ABCABBA".chars().collect();
+        let new: Vec<char> = "CBABAC".chars().collect();
+        let hunks = diff_slices(&old, &new);
+
+        // The example from Myers' paper has five edits.
+        assert_eq!(cost(&hunks), 5);
+        assert_eq!(patch(&old, &new, &hunks), new);
+    }
+
+    #[test]
+    fn test_trivial_cases() {
+        let text: Vec<char> = "same".chars().collect();
+        let none: Vec<char> = vec![];
+
+        assert!(diff_slices(&text, &text).is_empty());
+        assert_eq!(
+            diff_slices(&none, &text),
+            vec![Hunk {
+                old: 0..0,
+                new: 0..4
+            }]
+        );
+        assert_eq!(
+            diff_slices(&text, &['x']),
+            vec![Hunk {
+                old: 0..4,
+                new: 0..1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_text() {
+        let old = "fn main() {\n    println!(\"hi\");\n}\n";
+        let new = "fn main() {\n    println!(\"hello\");\n}\n// end\n";
+
+        assert_eq!(
+            diff_text(old, new),
+            vec![
+                Hunk {
+                    old: 27..28,
+                    new: 27..31
+                },
+                Hunk {
+                    old: 34..34,
+                    new: 37..44
+                },
+            ]
+        );
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nB\nc"),
+            vec![Hunk {
+                old: 2..4,
+                new: 2..4
+            }]
+        );
+        assert_eq!(
+            diff_chars("héllo", "hällo"),
+            vec![Hunk {
+                old: 1..3,
+                new: 1..3
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_pieces() {
+        let old = vec![('o', 0..10)];
+        let new = vec![('o', 0..4), ('i', 0..3), ('o', 6..10), ('o', 0..2)];
+
+        assert_eq!(
+            diff_pieces(&old, &new),
+            vec![
+                Hunk {
+                    old: 4..6,
+                    new: 4..7
+                },
+                Hunk {
+                    old: 10..10,
+                    new: 11..13
+                },
+            ]
+        );
+        assert!(diff_pieces(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_minimal_against_brute_force() {
+        let mut seed: u32 = 7;
+        let mut random = |limit: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) % limit
+        };
+
+        for _ in 0..300 {
+            let old: Vec<char> = (0..random(9))
+                .map(|_| (b'a' + random(3) as u8) as char)
+                .collect();
+            let new: Vec<char> = (0..random(9))
+                .map(|_| (b'a' + random(3) as u8) as char)
+                .collect();
+            let hunks = diff_slices(&old, &new);
+
+            assert_eq!(patch(&old, &new, &hunks), new);
+            assert_eq!(cost(&hunks), edit_distance(&old, &new));
+        }
+    }
+
+    // Insertions and deletions only, by dynamic programming.
+    fn edit_distance(old: &[char], new: &[char]) -> usize {
+        let mut previous: Vec<usize> = (0..=new.len()).collect();
+
+        for (i, a) in old.iter().enumerate() {
+            let mut row = vec![i + 1];
+
+            for (j, b) in new.iter().enumerate() {
+                let best = match a == b {
+                    true => previous[j],
+                    false => previous[j + 1].min(row[j]) + 1,
+                };
+                row.push(best);
+            }
+
+            previous = row;
+        }
+
+        return previous[new.len()];
+    }
+}
@@ -0,0 +1,580 @@
+use super::indexed_string::IndexedString;
+use super::Document;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::{char, error::Error, fmt, str};
+
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+// What Windows-1252 has in place of Latin-1's C1 controls, 0x80 to 0x9F.
+// Five of those bytes are left undefined.
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20AC}'),
+    None,
+    Some('\u{201A}'),
+    Some('\u{0192}'),
+    Some('\u{201E}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02C6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017D}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201C}'),
+    Some('\u{201D}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02DC}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203A}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017E}'),
+    Some('\u{0178}'),
+];
+
+// Converts between a file's bytes and the UTF-8 text a document holds.
+// Implement this to support encodings that aren't built in.
+pub trait Codec {
+    fn name(&self) -> &str;
+
+    // Byte order mark written before the text when saving with a BOM.
+    fn bom(&self) -> &[u8] {
+        return &[];
+    }
+
+    // With `lossy` set, bad input becomes U+FFFD and each substitution's
+    // byte offset in the decoded text is pushed onto `replacements`.
+    fn decode(
+        &self,
+        bytes: &[u8],
+        lossy: bool,
+        replacements: &mut Vec<usize>,
+    ) -> Result<String, DecodeError>;
+
+    // Appends the encoded text to `output`.
+    fn encode(&self, text: &str, output: &mut Vec<u8>) -> Result<(), EncodeError>;
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodeError {
+    pub encoding: String,
+    // Offset of the first bad byte in the input.
+    pub byte_offset: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EncodeError {
+    pub encoding: String,
+    // Offset of the unencodable character in the document.
+    pub byte_offset: usize,
+    pub character: char,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            fmt,
+            "Input isn't valid {} (at byte {})",
+            self.encoding, self.byte_offset
+        );
+    }
+}
+
+impl Error for DecodeError {}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            fmt,
+            "{:?} at byte {} can't be encoded as {}",
+            self.character, self.byte_offset, self.encoding
+        );
+    }
+}
+
+impl Error for EncodeError {}
+
+impl Encoding {
+    // Recognizes a byte order mark, returning the encoding it implies.
+    pub fn detect_bom(bytes: &[u8]) -> Option<Encoding> {
+        return [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be]
+            .iter()
+            .find(|encoding| bytes.starts_with(encoding.bom()))
+            .copied();
+    }
+
+    fn decode_error(&self, byte_offset: usize) -> DecodeError {
+        return DecodeError {
+            encoding: self.name().to_owned(),
+            byte_offset,
+        };
+    }
+
+    fn decode_utf8(
+        &self,
+        mut bytes: &[u8],
+        lossy: bool,
+        replacements: &mut Vec<usize>,
+    ) -> Result<String, DecodeError> {
+        let mut text = String::with_capacity(bytes.len());
+
+        loop {
+            match str::from_utf8(bytes) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    return Ok(text);
+                }
+                Err(error) => {
+                    let valid_bytes = error.valid_up_to();
+                    let consumed = text.len() + valid_bytes;
+
+                    if !lossy {
+                        // Replacements never happen in strict mode, so the
+                        // decoded length matches the input offset.
+                        return Err(self.decode_error(consumed));
+                    }
+
+                    text.push_str(str::from_utf8(&bytes[..valid_bytes]).unwrap());
+                    replacements.push(text.len());
+                    text.push(REPLACEMENT_CHARACTER);
+
+                    let skipped = error.error_len().unwrap_or(bytes.len() - valid_bytes);
+                    bytes = &bytes[valid_bytes + skipped..];
+                }
+            }
+        }
+    }
+
+    fn decode_utf16(
+        &self,
+        bytes: &[u8],
+        lossy: bool,
+        replacements: &mut Vec<usize>,
+    ) -> Result<String, DecodeError> {
+        let units = bytes.chunks_exact(2).map(|pair| match self {
+            Encoding::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+            _ => u16::from_le_bytes([pair[0], pair[1]]),
+        });
+
+        let mut text = String::with_capacity(bytes.len() / 2);
+        let mut byte_offset = 0;
+
+        for result in char::decode_utf16(units) {
+            match result {
+                Ok(character) => {
+                    text.push(character);
+                    byte_offset += character.len_utf16() * 2;
+                }
+                Err(_) if lossy => {
+                    replacements.push(text.len());
+                    text.push(REPLACEMENT_CHARACTER);
+                    byte_offset += 2;
+                }
+                Err(_) => return Err(self.decode_error(byte_offset)),
+            }
+        }
+
+        // A dangling odd byte can't be half of anything.
+        if bytes.len() % 2 == 1 {
+            if !lossy {
+                return Err(self.decode_error(bytes.len() - 1));
+            }
+
+            replacements.push(text.len());
+            text.push(REPLACEMENT_CHARACTER);
+        }
+
+        return Ok(text);
+    }
+
+    fn decode_windows_1252(
+        &self,
+        bytes: &[u8],
+        lossy: bool,
+        replacements: &mut Vec<usize>,
+    ) -> Result<String, DecodeError> {
+        let mut text = String::with_capacity(bytes.len());
+
+        for (byte_offset, byte) in bytes.iter().enumerate() {
+            let character = match byte {
+                0x80..=0x9F => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+                _ => Some(*byte as char),
+            };
+
+            match character {
+                Some(character) => text.push(character),
+                None if lossy => {
+                    replacements.push(text.len());
+                    text.push(REPLACEMENT_CHARACTER);
+                }
+                None => return Err(self.decode_error(byte_offset)),
+            }
+        }
+
+        return Ok(text);
+    }
+
+    fn encode_error(&self, byte_offset: usize, character: char) -> EncodeError {
+        return EncodeError {
+            encoding: self.name().to_owned(),
+            byte_offset,
+            character,
+        };
+    }
+}
+
+impl Codec for Encoding {
+    fn name(&self) -> &str {
+        return match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "ISO-8859-1",
+            Encoding::Windows1252 => "windows-1252",
+        };
+    }
+
+    fn bom(&self) -> &[u8] {
+        return match self {
+            Encoding::Utf8 => &[0xEF, 0xBB, 0xBF],
+            Encoding::Utf16Le => &[0xFF, 0xFE],
+            Encoding::Utf16Be => &[0xFE, 0xFF],
+            Encoding::Latin1 | Encoding::Windows1252 => &[],
+        };
+    }
+
+    fn decode(
+        &self,
+        bytes: &[u8],
+        lossy: bool,
+        replacements: &mut Vec<usize>,
+    ) -> Result<String, DecodeError> {
+        return match self {
+            Encoding::Utf8 => self.decode_utf8(bytes, lossy, replacements),
+            Encoding::Utf16Le | Encoding::Utf16Be => self.decode_utf16(bytes, lossy, replacements),
+            // Every byte is a code point, so there's nothing to get wrong.
+            Encoding::Latin1 => Ok(bytes.iter().map(|byte| *byte as char).collect()),
+            Encoding::Windows1252 => self.decode_windows_1252(bytes, lossy, replacements),
+        };
+    }
+
+    fn encode(&self, text: &str, output: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            Encoding::Utf8 => output.extend_from_slice(text.as_bytes()),
+            Encoding::Utf16Le => text
+                .encode_utf16()
+                .for_each(|unit| output.extend_from_slice(&unit.to_le_bytes())),
+            Encoding::Utf16Be => text
+                .encode_utf16()
+                .for_each(|unit| output.extend_from_slice(&unit.to_be_bytes())),
+            Encoding::Latin1 => {
+                for (byte_offset, character) in text.char_indices() {
+                    if character as u32 > 0xFF {
+                        return Err(self.encode_error(byte_offset, character));
+                    }
+
+                    output.push(character as u8);
+                }
+            }
+            Encoding::Windows1252 => {
+                for (byte_offset, character) in text.char_indices() {
+                    let byte = match character as u32 {
+                        0x80..=0x9F => None,
+                        code if code <= 0xFF => Some(code as u8),
+                        _ => WINDOWS_1252_HIGH
+                            .iter()
+                            .position(|high| *high == Some(character))
+                            .map(|index| 0x80 + index as u8),
+                    };
+
+                    match byte {
+                        Some(byte) => output.push(byte),
+                        None => return Err(self.encode_error(byte_offset, character)),
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl Document {
+    // Decodes a file, picking the encoding from its byte order mark and
+    // falling back to UTF-8.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let encoding = Encoding::detect_bom(bytes).unwrap_or(Encoding::Utf8);
+        let (document, _) = Document::from_bytes_with(bytes, Arc::new(encoding), false)?;
+
+        return Ok(document);
+    }
+
+    // Like `from_bytes`, but substitutes U+FFFD for bad input. Also returns
+    // where the substitutions were made.
+    pub fn from_bytes_lossy(bytes: &[u8]) -> (Self, Vec<usize>) {
+        let encoding = Encoding::detect_bom(bytes).unwrap_or(Encoding::Utf8);
+
+        return Document::from_bytes_with(bytes, Arc::new(encoding), true)
+            .expect("Lossy decoding can't fail");
+    }
+
+    // Decodes with a specific codec. A leading BOM for that codec is skipped
+    // and remembered so saving can write it back.
+    pub fn from_bytes_with(
+        bytes: &[u8],
+        codec: Arc<dyn Codec + Send + Sync>,
+        lossy: bool,
+    ) -> Result<(Self, Vec<usize>), DecodeError> {
+        let bom = codec.bom();
+        let has_bom = !bom.is_empty() && bytes.starts_with(bom);
+        let body = match has_bom {
+            true => &bytes[bom.len()..],
+            false => bytes,
+        };
+
+        let mut replacements = vec![];
+        let text = codec
+            .decode(body, lossy, &mut replacements)
+            .map_err(|mut error| {
+                error.byte_offset += bytes.len() - body.len();
+                error
+            })?;
+
+        let mut document = Document::from_original(IndexedString::from_owned(text));
+        document.set_encoding(codec, has_bom);
+
+        return Ok((document, replacements));
+    }
+
+    pub fn encoding(&self) -> &dyn Codec {
+        return &*self.encoding;
+    }
+
+    pub fn has_bom(&self) -> bool {
+        return self.bom;
+    }
+
+    // Changes how the document is saved, e.g. for "Save with encoding".
+    pub fn set_encoding(&mut self, codec: Arc<dyn Codec + Send + Sync>, bom: bool) {
+        self.encoding = codec;
+        self.bom = bom;
+    }
+
+    // Streams the document in its encoding, BOM first if it had one.
+    // Unencodable text fails with `io::ErrorKind::InvalidData` wrapping an
+    // `EncodeError`.
+    pub fn write_encoded_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.bom {
+            writer.write_all(self.encoding.bom())?;
+        }
+
+        let mut encoded = vec![];
+        let mut offset = 0;
+
+        for chunk in self.chunks(&(0..self.len())) {
+            encoded.clear();
+            self.encoding
+                .encode(chunk, &mut encoded)
+                .map_err(|mut error| {
+                    error.byte_offset += offset;
+                    io::Error::new(io::ErrorKind::InvalidData, error)
+                })?;
+
+            writer.write_all(&encoded)?;
+            offset += chunk.len();
+        }
+
+        return Ok(());
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut output = vec![];
+
+        return match self.write_encoded_to(&mut output) {
+            Ok(()) => Ok(output),
+            Err(error) => Err(*error
+                .into_inner()
+                .and_then(|inner| inner.downcast::<EncodeError>().ok())
+                .expect("Writing to a Vec only fails on encoding errors")),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shifts ASCII letters by one. Stands in for a third-party codec.
+    struct Shifted;
+
+    impl Codec for Shifted {
+        fn name(&self) -> &str {
+            return "shifted";
+        }
+
+        fn decode(&self, bytes: &[u8], _: bool, _: &mut Vec<usize>) -> Result<String, DecodeError> {
+            return Ok(bytes.iter().map(|byte| (byte - 1) as char).collect());
+        }
+
+        fn encode(&self, text: &str, output: &mut Vec<u8>) -> Result<(), EncodeError> {
+            output.extend(text.bytes().map(|byte| byte + 1));
+            return Ok(());
+        }
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        return text
+            .encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes().to_vec())
+            .collect();
+    }
+
+    #[test]
+    fn test_utf8_without_bom() {
+        let text = Document::from_bytes("plain ☃".as_bytes()).unwrap();
+
+        assert_eq!(text.to_string(), "plain ☃");
+        assert_eq!(text.encoding().name(), "UTF-8");
+        assert!(!text.has_bom());
+    }
+
+    #[test]
+    fn test_utf8_bom_round_trip() {
+        let bytes = b"\xEF\xBB\xBFtext";
+        let text = Document::from_bytes(bytes).unwrap();
+
+        assert_eq!(text.to_string(), "text");
+        assert!(text.has_bom());
+        assert_eq!(text.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_utf16le_round_trip() {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(utf16le("wide 🎉 text"));
+
+        let mut text = Document::from_bytes(&bytes).unwrap();
+        assert_eq!(text.to_string(), "wide 🎉 text");
+        assert_eq!(text.encoding().name(), "UTF-16LE");
+
+        text.insert(0, "so ");
+        let mut expected = vec![0xFF, 0xFE];
+        expected.extend(utf16le("so wide 🎉 text"));
+        assert_eq!(text.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_utf16be() {
+        let text = Document::from_bytes(b"\xFE\xFF\x00h\x00i").unwrap();
+
+        assert_eq!(text.to_string(), "hi");
+        assert_eq!(text.encoding().name(), "UTF-16BE");
+    }
+
+    #[test]
+    fn test_latin1() {
+        let (text, _) =
+            Document::from_bytes_with(b"caf\xE9", Arc::new(Encoding::Latin1), false).unwrap();
+
+        assert_eq!(text.to_string(), "café");
+        assert_eq!(text.to_bytes().unwrap(), b"caf\xE9");
+    }
+
+    #[test]
+    fn test_latin1_rejects_wide_characters() {
+        let (mut text, _) =
+            Document::from_bytes_with(b"abc", Arc::new(Encoding::Latin1), false).unwrap();
+        text.insert(1, "☃");
+
+        let error = text.to_bytes().unwrap_err();
+        assert_eq!(error.byte_offset, 1);
+        assert_eq!(error.character, '☃');
+    }
+
+    #[test]
+    fn test_windows_1252() {
+        let bytes = b"\x93caf\xE9\x94 \x80 5\x96\x99";
+        let (mut text, _) =
+            Document::from_bytes_with(bytes, Arc::new(Encoding::Windows1252), false).unwrap();
+
+        assert_eq!(text.to_string(), "\u{201C}café\u{201D} € 5–™");
+        assert_eq!(text.encoding().name(), "windows-1252");
+        assert_eq!(text.to_bytes().unwrap(), bytes);
+
+        // C1 controls exist in Latin-1 but not here.
+        text.insert(0, "\u{0081}");
+        assert_eq!(text.to_bytes().unwrap_err().character, '\u{0081}');
+    }
+
+    #[test]
+    fn test_windows_1252_undefined_bytes() {
+        let codec = Arc::new(Encoding::Windows1252);
+        let error = Document::from_bytes_with(b"ab\x81", codec.clone(), false)
+            .err()
+            .unwrap();
+        assert_eq!(error.byte_offset, 2);
+
+        let (text, replacements) =
+            Document::from_bytes_with(b"a\x8D\x8Fb\x90\x9D", codec, true).unwrap();
+        assert_eq!(text.to_string(), "a\u{FFFD}\u{FFFD}b\u{FFFD}\u{FFFD}");
+        assert_eq!(replacements, vec![1, 4, 8, 11]);
+    }
+
+    #[test]
+    fn test_strict_decoding_reports_position() {
+        let error = Document::from_bytes(b"\xEF\xBB\xBFab\xFFcd").err().unwrap();
+
+        assert_eq!(error.byte_offset, 5);
+        assert_eq!(error.encoding, "UTF-8");
+    }
+
+    #[test]
+    fn test_lossy_utf8_records_replacements() {
+        let (text, replacements) = Document::from_bytes_lossy(b"a\xFFb\xE2\x98");
+
+        assert_eq!(text.to_string(), "a\u{FFFD}b\u{FFFD}");
+        assert_eq!(replacements, vec![1, 5]);
+    }
+
+    #[test]
+    fn test_lossy_utf16_records_replacements() {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(utf16le("ab"));
+        bytes.extend(&[0x00, 0xD8]); // Unpaired surrogate.
+        bytes.extend(utf16le("c"));
+        bytes.push(0x00);
+
+        let (text, replacements) = Document::from_bytes_lossy(&bytes);
+        assert_eq!(text.to_string(), "ab\u{FFFD}c\u{FFFD}");
+        assert_eq!(replacements, vec![2, 6]);
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let (mut text, _) = Document::from_bytes_with(b"ifmmp", Arc::new(Shifted), false).unwrap();
+        assert_eq!(text.to_string(), "hello");
+
+        text.insert(5, "!");
+        assert_eq!(text.to_bytes().unwrap(), b"ifmmp\"");
+    }
+}
@@ -0,0 +1,697 @@
+use super::buffers::{BufferId, BufferRegistry};
+use super::changes::{Change, ChangeLog};
+use super::crc32::crc32;
+use super::fragment::{Fragment, Source};
+use super::fragment_map::FragmentMap;
+use super::indexed_string::IndexedString;
+use super::Document;
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::{error::Error, fmt};
+
+// A fast save file holds the piece table rather than the text: a header
+// naming the original file by its length and checksum, then one record per
+// save. The first record carries the edit history, insertions, buffers and
+// fragments in full; each later one carries only the edits and bytes added
+// since, plus the whole fragment list. Reopening replays the records on top
+// of the original.
+//
+// Header: magic, format version (u16), original length (u64), original
+// CRC-32 (u32), CRC-32 of the preceding header bytes (u32).
+//
+// Record: payload length (u64), payload, CRC-32 of the payload (u32).
+//
+// Payload, in order:
+// - The document version at the previous record (u64) and now (u64), then
+//   how many of the edits in between follow (u64) and each one's start,
+//   deleted and inserted byte counts (u64). Edits the document no longer
+//   remembered are left out.
+// - Where the new insertion bytes start (u64), their length (u64) and the
+//   bytes.
+// - How many buffers grew (u64, 0 or 1) and for each its index (u64), where
+//   the new bytes start (u64), their length (u64) and the bytes. Only the
+//   buffer being typed into grows.
+// - The index of the first new buffer (u64), how many there are (u64) and
+//   each one's length (u64) and bytes.
+// - The fragment count (u64) and each fragment's source tag (u8), buffer
+//   index (u64, buffers only), offset (u64) and length (u64).
+//
+// Integers are little-endian.
+const MAGIC: &[u8; 4] = b"PTFS";
+const FORMAT_VERSION: u16 = 2;
+
+const TAG_ORIGINAL: u8 = 0;
+const TAG_INSERTION: u8 = 1;
+const TAG_BUFFER: u8 = 2;
+
+#[derive(Debug)]
+pub enum FastSaveError {
+    Io(io::Error),
+    // The file doesn't start with the fast save magic bytes.
+    NotAFastSave,
+    // Written by a newer version of the format.
+    // (format_version)
+    UnsupportedVersion(u16),
+    // The original text isn't the one the file was saved against.
+    OriginalChanged,
+    // A checksum didn't match or the file was cut short.
+    // (what_was_wrong)
+    Corrupt(&'static str),
+}
+
+// How much of a document a fast save file already holds, so the next save
+// can append just what's new. Only meaningful for the document it came from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FastSaveState {
+    insertions: usize,
+    buffers: usize,
+    // The buffer being typed into and how much of it was written.
+    // (buffer_index, written_bytes)
+    typing: Option<(usize, usize)>,
+    version: u64,
+}
+
+impl fmt::Display for FastSaveError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            FastSaveError::Io(error) => write!(fmt, "Failed to read fast save: {}", error),
+            FastSaveError::NotAFastSave => write!(fmt, "Not a fast save file"),
+            FastSaveError::UnsupportedVersion(version) => {
+                write!(fmt, "Unsupported fast save format version {}", version)
+            }
+            FastSaveError::OriginalChanged => {
+                write!(fmt, "The original file has changed since the fast save")
+            }
+            FastSaveError::Corrupt(reason) => write!(fmt, "Fast save file is corrupt: {}", reason),
+        };
+    }
+}
+
+impl Error for FastSaveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            FastSaveError::Io(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<io::Error> for FastSaveError {
+    fn from(error: io::Error) -> Self {
+        return FastSaveError::Io(error);
+    }
+}
+
+impl Document {
+    // Writes a complete fast save file. The original text isn't included;
+    // reopening needs it back from wherever it was loaded.
+    pub fn fast_save<W: Write>(&self, writer: &mut W) -> io::Result<FastSaveState> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.original.len() as u64).to_le_bytes());
+        header.extend_from_slice(&self.original_checksum().to_le_bytes());
+        header.extend_from_slice(&crc32(&header).to_le_bytes());
+        writer.write_all(&header)?;
+
+        let mut state = FastSaveState {
+            insertions: 0,
+            buffers: 0,
+            typing: None,
+            version: 0,
+        };
+        self.fast_save_append(&mut state, writer)?;
+
+        return Ok(state);
+    }
+
+    // Appends a record with the text added since `state` was taken and the
+    // current fragments. `writer` must be positioned at the end of the file
+    // `state` belongs to. The file grows with each save; a full `fast_save`
+    // compacts it.
+    pub fn fast_save_append<W: Write>(
+        &self,
+        state: &mut FastSaveState,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let typed = state.typing.filter(|&(index, written)| {
+            index < state.buffers.min(self.buffers.len())
+                && written <= self.buffers.get(BufferId::from_index(index)).len()
+        });
+
+        if state.insertions > self.insertions.len()
+            || state.buffers > self.buffers.len()
+            || state.version > self.version()
+            || typed != state.typing
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Fast save state belongs to another document",
+            ));
+        }
+
+        let mut payload = vec![];
+        // Older edits may have been forgotten, in which case only the
+        // version moves on.
+        let changes = self.changes_since(state.version).unwrap_or_default();
+        put_u64(&mut payload, state.version as usize);
+        put_u64(&mut payload, self.version() as usize);
+        put_u64(&mut payload, changes.len());
+
+        for change in &changes {
+            put_u64(&mut payload, change.start);
+            put_u64(&mut payload, change.deleted);
+            put_u64(&mut payload, change.inserted);
+        }
+
+        let insertions = &self.insertions;
+        let new_text = insertions.slice_bytes(state.insertions..insertions.len());
+        put_u64(&mut payload, state.insertions);
+        put_u64(&mut payload, new_text.len());
+        payload.extend_from_slice(new_text);
+
+        let grown = typed.filter(|&(index, written)| {
+            self.buffers.get(BufferId::from_index(index)).len() > written
+        });
+        put_u64(&mut payload, grown.iter().count());
+
+        if let Some((index, written)) = grown {
+            let buffer = self.buffers.get(BufferId::from_index(index));
+            put_u64(&mut payload, index);
+            put_u64(&mut payload, written);
+            put_u64(&mut payload, buffer.len() - written);
+            payload.extend_from_slice(buffer.slice_bytes(written..buffer.len()));
+        }
+
+        let buffers: Vec<_> = self.buffers.iter().skip(state.buffers).collect();
+        put_u64(&mut payload, state.buffers);
+        put_u64(&mut payload, buffers.len());
+
+        for (_, buffer) in &buffers {
+            put_u64(&mut payload, buffer.len());
+            payload.extend_from_slice(buffer.slice_bytes(0..buffer.len()));
+        }
+
+        put_u64(&mut payload, self.fragments.len());
+
+        for frag in self.fragments.values() {
+            match frag.source {
+                Source::Original => payload.push(TAG_ORIGINAL),
+                Source::Insertion => payload.push(TAG_INSERTION),
+                Source::Buffer(id) => {
+                    payload.push(TAG_BUFFER);
+                    put_u64(&mut payload, id.index());
+                }
+            }
+
+            put_u64(&mut payload, frag.byte_offset);
+            put_u64(&mut payload, frag.byte_length);
+        }
+
+        let mut record = Vec::with_capacity(payload.len() + 12);
+        put_u64(&mut record, payload.len());
+        record.extend_from_slice(&payload);
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+        writer.write_all(&record)?;
+
+        // The buffer being typed into may still grow, so the next record
+        // writes whatever is added to it.
+        state.insertions = self.insertions.len();
+        state.buffers = self.buffers.len();
+        state.typing = match self.typing {
+            Source::Buffer(id) => Some((id.index(), self.buffers.get(id).len())),
+            _ => None,
+        };
+        state.version = self.version();
+
+        return Ok(());
+    }
+
+    // Restores a fast saved document on top of its original, which must be
+    // unedited. The encoding and line break settings are kept from it. The
+    // version and remembered edits are restored too, so `changes_since`
+    // answers as it did for the saved document.
+    pub fn open_fast_save<R: Read>(
+        original: Document,
+        mut reader: R,
+    ) -> Result<(Document, FastSaveState), FastSaveError> {
+        let mut header = [0; 22];
+        read_exact(&mut reader, &mut header)?;
+
+        if &header[..4] != MAGIC {
+            return Err(FastSaveError::NotAFastSave);
+        }
+
+        let mut fields = Fields::new(&header[4..]);
+        let checked = fields.u16()?;
+        let original_length = fields.u64()?;
+        let original_checksum = fields.u32()?;
+        let header_checksum = fields.u32()?;
+
+        if crc32(&header[..18]) != header_checksum {
+            return Err(FastSaveError::Corrupt("bad header checksum"));
+        }
+
+        if checked != FORMAT_VERSION {
+            return Err(FastSaveError::UnsupportedVersion(checked));
+        }
+
+        if original_length != original.original.len()
+            || original_checksum != original.original_checksum()
+        {
+            return Err(FastSaveError::OriginalChanged);
+        }
+
+        let mut document = original;
+        let mut version = 0;
+        let mut history = vec![];
+        let mut insertions = IndexedString::new();
+        let mut buffers: Vec<IndexedString> = vec![];
+        let mut fragments = None;
+
+        while let Some(payload) = read_record(&mut reader)? {
+            let mut fields = Fields::new(&payload);
+
+            if fields.u64()? as u64 != version {
+                return Err(FastSaveError::Corrupt("history out of sequence"));
+            }
+
+            let next_version = fields.u64()? as u64;
+            let count = fields.u64()?;
+
+            if next_version < version || count as u64 > next_version - version {
+                return Err(FastSaveError::Corrupt("history out of sequence"));
+            }
+
+            // Edits are missing, so the ones before them lead nowhere.
+            if (count as u64) < next_version - version {
+                history.clear();
+            }
+
+            for _ in 0..count {
+                history.push(Change {
+                    start: fields.u64()?,
+                    deleted: fields.u64()?,
+                    inserted: fields.u64()?,
+                });
+            }
+
+            version = next_version;
+
+            if fields.u64()? != insertions.len() {
+                return Err(FastSaveError::Corrupt("insertions out of sequence"));
+            }
+
+            let length = fields.u64()?;
+            insertions.append(fields.text(length)?);
+
+            for _ in 0..fields.u64()? {
+                let index = fields.u64()?;
+                let start = fields.u64()?;
+
+                match buffers.get_mut(index) {
+                    Some(buffer) if buffer.len() == start => {
+                        let length = fields.u64()?;
+                        buffer.append(fields.text(length)?);
+                    }
+                    _ => return Err(FastSaveError::Corrupt("buffer text out of sequence")),
+                }
+            }
+
+            if fields.u64()? != buffers.len() {
+                return Err(FastSaveError::Corrupt("buffers out of sequence"));
+            }
+
+            for _ in 0..fields.u64()? {
+                let length = fields.u64()?;
+                buffers.push(IndexedString::from(fields.text(length)?));
+            }
+
+            let count = fields.u64()?;
+            let mut list = vec![];
+
+            for _ in 0..count {
+                let source = match fields.u8()? {
+                    TAG_ORIGINAL => Source::Original,
+                    TAG_INSERTION => Source::Insertion,
+                    TAG_BUFFER => match fields.u64()? {
+                        index if index < buffers.len() => {
+                            Source::Buffer(BufferId::from_index(index))
+                        }
+                        _ => return Err(FastSaveError::Corrupt("unknown buffer")),
+                    },
+                    _ => return Err(FastSaveError::Corrupt("unknown fragment source")),
+                };
+
+                list.push(Fragment::new(source, fields.u64()?, fields.u64()?));
+            }
+
+            if !fields.is_done() {
+                return Err(FastSaveError::Corrupt("unexpected bytes in record"));
+            }
+
+            fragments = Some(list);
+        }
+
+        let fragments = fragments.ok_or(FastSaveError::Corrupt("no records"))?;
+        document.insertions = Arc::new(insertions);
+        document.buffers = BufferRegistry::new();
+
+        for buffer in buffers {
+            document.buffers.register(&Arc::new(buffer));
+        }
+
+        // `is_modified` still compares against the original, so a document
+        // with edits counts as modified.
+        document.fragments = FragmentMap::restore(fragments, ChangeLog::restore(version, history));
+
+        if document.check_invariants().is_err() {
+            return Err(FastSaveError::Corrupt("fragments don't fit the text"));
+        }
+
+        document.after_edit();
+
+        let state = FastSaveState {
+            insertions: document.insertions.len(),
+            buffers: document.buffers.len(),
+            typing: None,
+            version,
+        };
+
+        return Ok((document, state));
+    }
+
+    pub(crate) fn original_checksum(&self) -> u32 {
+        return crc32(self.original.slice_bytes(0..self.original.len()));
+    }
+}
+
+fn put_u64(output: &mut Vec<u8>, value: usize) {
+    output.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+// Like `Read::read_exact`, but running out of input means the file is
+// truncated rather than an I/O failure.
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), FastSaveError> {
+    return reader
+        .read_exact(buffer)
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => FastSaveError::Corrupt("file is truncated"),
+            _ => FastSaveError::Io(error),
+        });
+}
+
+// The next record's payload, or `None` at the end of the file.
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, FastSaveError> {
+    let mut length = [0; 8];
+    let mut filled = 0;
+
+    while filled < length.len() {
+        match reader.read(&mut length[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(FastSaveError::Corrupt("file is truncated")),
+            Ok(count) => filled += count,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(FastSaveError::Io(error)),
+        }
+    }
+
+    // Read through `take` so a damaged length can't allocate a huge buffer
+    // up front.
+    let length = u64::from_le_bytes(length);
+    let mut payload = vec![];
+    reader.by_ref().take(length).read_to_end(&mut payload)?;
+
+    if payload.len() as u64 != length {
+        return Err(FastSaveError::Corrupt("file is truncated"));
+    }
+
+    let mut checksum = [0; 4];
+    read_exact(reader, &mut checksum)?;
+
+    if crc32(&payload) != u32::from_le_bytes(checksum) {
+        return Err(FastSaveError::Corrupt("bad record checksum"));
+    }
+
+    return Ok(Some(payload));
+}
+
+// Reads little-endian fields off the front of a byte slice.
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Fields { bytes }
+    }
+
+    fn is_done(&self) -> bool {
+        return self.bytes.is_empty();
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], FastSaveError> {
+        if length > self.bytes.len() {
+            return Err(FastSaveError::Corrupt("record is truncated"));
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> Result<u8, FastSaveError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u16(&mut self) -> Result<u16, FastSaveError> {
+        let bytes = self.take(2)?;
+
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn u32(&mut self) -> Result<u32, FastSaveError> {
+        let bytes = self.take(4)?.try_into().expect("Took four bytes");
+
+        return Ok(u32::from_le_bytes(bytes));
+    }
+
+    fn u64(&mut self) -> Result<usize, FastSaveError> {
+        let bytes = self.take(8)?.try_into().expect("Took eight bytes");
+
+        return usize::try_from(u64::from_le_bytes(bytes))
+            .map_err(|_| FastSaveError::Corrupt("value out of range"));
+    }
+
+    fn text(&mut self, length: usize) -> Result<&'a str, FastSaveError> {
+        return std::str::from_utf8(self.take(length)?)
+            .map_err(|_| FastSaveError::Corrupt("text isn't valid UTF-8"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edited() -> Document {
+        let mut text = Document::from("original text");
+        text.insert(9, "inserted ");
+        text.delete(&(0..2));
+        text.include(text.len(), " included".to_owned());
+
+        return text;
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = edited();
+        let mut file = vec![];
+        let saved = text.fast_save(&mut file).unwrap();
+
+        let original = Document::from("original text");
+        let (reopened, state) = Document::open_fast_save(original, &file[..]).unwrap();
+
+        assert_eq!(reopened.to_string(), "iginal inserted text included");
+        assert_eq!(state, saved);
+        assert_eq!(
+            reopened.fragments.values().collect::<Vec<_>>(),
+            text.fragments.values().collect::<Vec<_>>()
+        );
+        assert!(reopened.is_modified());
+        assert_eq!(reopened.version(), text.version());
+        assert_eq!(reopened.changes_since(0), text.changes_since(0));
+    }
+
+    #[test]
+    fn test_history_survives_appends() {
+        let mut text = edited();
+        let mut file = vec![];
+        let mut state = text.fast_save(&mut file).unwrap();
+        let saved = text.version();
+
+        text.insert(0, "or");
+        text.fast_save_append(&mut state, &mut file).unwrap();
+        text.delete(&(9..18));
+        text.fast_save_append(&mut state, &mut file).unwrap();
+
+        let original = Document::from("original text");
+        let (reopened, _) = Document::open_fast_save(original, &file[..]).unwrap();
+        assert_eq!(reopened.version(), text.version());
+        assert_eq!(reopened.changes_since(saved), text.changes_since(saved));
+        assert_eq!(reopened.changes_since(0), text.changes_since(0));
+    }
+
+    #[test]
+    fn test_forgotten_history() {
+        let mut text = Document::from("text");
+        let mut file = vec![];
+        let mut state = text.fast_save(&mut file).unwrap();
+
+        for _ in 0..1500 {
+            text.insert(0, "x");
+        }
+        text.fast_save_append(&mut state, &mut file).unwrap();
+        text.insert(0, "y");
+        text.fast_save_append(&mut state, &mut file).unwrap();
+
+        let (reopened, _) = Document::open_fast_save(Document::from("text"), &file[..]).unwrap();
+        assert_eq!(reopened.version(), 1501);
+        assert_eq!(reopened.changes_since(0), None);
+        assert_eq!(reopened.changes_since(1500), text.changes_since(1500));
+        assert_eq!(reopened.to_string(), text.to_string());
+    }
+
+    #[test]
+    fn test_appending_writes_only_new_text() {
+        let mut text = edited();
+        let mut file = vec![];
+        let mut state = text.fast_save(&mut file).unwrap();
+        let full_size = file.len();
+
+        text.insert(0, "or");
+        text.delete(&(9..18));
+        text.fast_save_append(&mut state, &mut file).unwrap();
+
+        // The new record holds "or" and the fragments, not the old text.
+        assert!(file.len() - full_size < full_size);
+        assert!(!file[full_size..]
+            .windows(8)
+            .any(|bytes| bytes == b"inserted"));
+
+        let original = Document::from("original text");
+        let (reopened, _) = Document::open_fast_save(original, &file[..]).unwrap();
+        assert_eq!(reopened.to_string(), text.to_string());
+        assert_eq!(reopened.to_string(), "original text included");
+    }
+
+    #[test]
+    fn test_typing_after_a_copy() {
+        let mut text = edited();
+        let clip = text.copy(&(0..text.len()));
+        text.insert(0, "[");
+        let mut file = vec![];
+        let mut state = text.fast_save(&mut file).unwrap();
+
+        // This goes to the same buffer as "[", which was already written.
+        text.insert(text.len(), "]");
+        text.paste(1, &clip);
+        text.fast_save_append(&mut state, &mut file).unwrap();
+
+        let original = Document::from("original text");
+        let (reopened, _) = Document::open_fast_save(original, &file[..]).unwrap();
+        assert_eq!(reopened.to_string(), text.to_string());
+    }
+
+    #[test]
+    fn test_typing_buffer_is_written_once() {
+        let mut text = edited();
+        let clip = text.copy(&(0..text.len()));
+        // The insertions are shared with the clip, so this starts a buffer.
+        text.insert(0, "typed once ");
+        let mut file = vec![];
+        let mut state = text.fast_save(&mut file).unwrap();
+        let full_size = file.len();
+
+        text.insert(text.len(), " and more");
+        text.fast_save_append(&mut state, &mut file).unwrap();
+        let second_size = file.len();
+
+        // Sharing the buffer starts another one, after the first grew again.
+        text.insert(0, "> ");
+        let _second_clip = text.copy(&(0..2));
+        text.insert(0, "new ");
+        text.paste(0, &clip);
+        text.fast_save_append(&mut state, &mut file).unwrap();
+
+        let records = [&file[full_size..second_size], &file[second_size..]];
+        assert!(records
+            .iter()
+            .all(|record| !record.windows(10).any(|bytes| bytes == b"typed once")));
+        assert!(!records[1].windows(8).any(|bytes| bytes == b"and more"));
+
+        let original = Document::from("original text");
+        let (reopened, _) = Document::open_fast_save(original, &file[..]).unwrap();
+        assert_eq!(reopened.to_string(), text.to_string());
+    }
+
+    #[test]
+    fn test_empty_document() {
+        let text = Document::new();
+        let mut file = vec![];
+        text.fast_save(&mut file).unwrap();
+
+        let (reopened, _) = Document::open_fast_save(Document::new(), &file[..]).unwrap();
+        assert!(reopened.is_empty());
+    }
+
+    #[test]
+    fn test_changed_original() {
+        let mut file = vec![];
+        edited().fast_save(&mut file).unwrap();
+
+        let original = Document::from("original test");
+        let error = Document::open_fast_save(original, &file[..]).err().unwrap();
+
+        assert!(matches!(error, FastSaveError::OriginalChanged));
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let mut file = vec![];
+        edited().fast_save(&mut file).unwrap();
+
+        let open = |file: &[u8]| {
+            let original = Document::from("original text");
+            Document::open_fast_save(original, file).err().unwrap()
+        };
+
+        assert!(matches!(open(b"text"), FastSaveError::Corrupt(_)));
+        assert!(matches!(
+            open(b"not a fast save file at all"),
+            FastSaveError::NotAFastSave
+        ));
+        assert!(matches!(
+            open(&file[..file.len() - 1]),
+            FastSaveError::Corrupt("file is truncated")
+        ));
+
+        let mut damaged = file.clone();
+        damaged[30] ^= 1;
+        assert!(matches!(
+            open(&damaged),
+            FastSaveError::Corrupt("bad record checksum")
+        ));
+
+        let mut newer = file.clone();
+        newer[4] = 3;
+        let checksum = crc32(&newer[..18]).to_le_bytes();
+        newer[18..22].copy_from_slice(&checksum);
+        assert!(matches!(open(&newer), FastSaveError::UnsupportedVersion(3)));
+
+        assert_eq!(
+            open(&file[..file.len() - 1]).to_string(),
+            "Fast save file is corrupt: file is truncated"
+        );
+    }
+}
@@ -1,9 +1,12 @@
+use super::buffers::BufferId;
 use super::indexed_string::IndexedString;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Source {
     Insertion,
     Original,
+    // Read-only text in the document's buffer registry.
+    Buffer(BufferId),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -32,11 +35,10 @@ impl Fragment {
         return Fragment::new(Source::Insertion, offset, size);
     }
 
-    pub fn get_slice(&self, source: &IndexedString) -> String {
-        let source_text = source.to_string();
+    pub fn get_slice<'a>(&self, source: &'a IndexedString) -> &'a str {
         let end_byte = self.byte_offset + self.byte_length;
 
-        return source_text[self.byte_offset..end_byte].to_owned();
+        return source.slice(self.byte_offset..end_byte);
     }
 
     pub fn resize(&mut self, offset: usize, length: usize) {
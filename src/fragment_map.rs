@@ -0,0 +1,782 @@
+use super::changes::{Change, ChangeLog};
+use super::fragment::{Fragment, Source};
+use std::collections::BTreeMap;
+use std::ops::{Deref, Range};
+
+pub type Selector<'a> = (&'a usize, &'a Fragment);
+
+#[derive(Debug)]
+pub struct DeletionRange {
+    pub fragment: Range<usize>,
+    pub deletion: Range<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FragmentOperation {
+    // Insert a fragment into another fragment.
+    // (byte_offset_from_start, Fragment)
+    Insert(usize, Fragment),
+    // Split the fragment into two parts.
+    // (split_at_byte, resume_at_byte)
+    Split(usize, usize),
+    // Remove fragment text from the beginning, end, or both.
+    // (trim_start_bytes, trim_end_bytes)
+    Trim(usize, usize),
+    // Remove the fragment. Metadata just for convenience.
+    // (deleted_byte_count)
+    Delete(usize),
+    // Swap the bytes between two document offsets for other fragments.
+    // (stop_at_byte, resume_at_byte, replacement)
+    Replace(usize, usize, Vec<Fragment>),
+    // No change.
+    None,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct FragmentUpdate {
+    pub operation: FragmentOperation,
+    pub move_to: usize,
+    pub key: usize,
+}
+
+// The fragments of a piece table keyed by their offset in the text. Knows
+// nothing about the buffers behind them, so any byte-oriented table can use
+// it for planning and applying edits.
+#[derive(Clone)]
+pub struct FragmentMap {
+    map: BTreeMap<usize, Fragment>,
+    changes: ChangeLog,
+}
+
+impl FragmentMap {
+    pub fn new(initial_fragment: Fragment) -> Self {
+        let mut map = BTreeMap::new();
+        map.insert(0, initial_fragment);
+
+        return FragmentMap {
+            map,
+            changes: ChangeLog::new(),
+        };
+    }
+
+    // Rebuilds a map from its fragments in text order, along with the edits
+    // that led to it.
+    pub fn restore(fragments: Vec<Fragment>, changes: ChangeLog) -> Self {
+        let mut map = BTreeMap::new();
+        let mut offset = 0;
+
+        for frag in fragments {
+            let length = frag.byte_length;
+            map.insert(offset, frag);
+            offset += length;
+        }
+
+        return FragmentMap { map, changes };
+    }
+
+    pub fn changes(&self) -> &ChangeLog {
+        return &self.changes;
+    }
+
+    // Bypasses the change log, for tests that break the invariants on
+    // purpose. Edits go through `splice`, `delete` or `insert_fragment`.
+    #[cfg(test)]
+    pub fn map_mut(&mut self) -> &mut BTreeMap<usize, Fragment> {
+        return &mut self.map;
+    }
+
+    // Total bytes of text the fragments cover.
+    pub fn byte_length(&self) -> usize {
+        let (last_offset, last_fragment) = self
+            .map
+            .iter()
+            .next_back()
+            .expect("Somehow the buffer text doesn't have a fragment.");
+
+        return last_offset + last_fragment.byte_length;
+    }
+
+    // Replaces a range with other fragments. The range must be in bounds.
+    // Returns the fragments that were cut out.
+    pub fn splice(&mut self, range: &Range<usize>, pieces: Vec<Fragment>) -> Vec<Fragment> {
+        let removed = self.slice_fragments(range);
+
+        let inserted_bytes: usize = pieces.iter().map(|frag| frag.byte_length).sum();
+        let changes = self.get_changes_for_replacement(range, pieces);
+        self.record(range.start, &changes, inserted_bytes);
+        let (first, rest) = changes.split_first().expect("Empty fragment set");
+
+        // Lift out the target so nothing shifted onto its key collides.
+        let target = self.map.remove(&first.key).expect("Missing fragment");
+
+        // Make room before filling the gap. Growing pushes later fragments
+        // up, so start from the back; shrinking pulls them down, so start
+        // from the front.
+        if inserted_bytes > range.end - range.start {
+            rest.iter().rev().for_each(|change| {
+                self.apply_change(change);
+            });
+        } else {
+            rest.iter().for_each(|change| {
+                self.apply_change(change);
+            });
+        }
+
+        if let FragmentOperation::Replace(stop, resume, pieces) = &first.operation {
+            self.replace_in_fragment(target, first, (stop, resume), pieces);
+        }
+
+        self.ensure_fragment();
+
+        return removed;
+    }
+
+    pub fn delete(&mut self, range: &Range<usize>) {
+        let changes = self.get_changes_for_deletion(range);
+        self.record(range.start, &changes, 0);
+
+        for change in changes {
+            self.apply_change(&change);
+        }
+
+        self.ensure_fragment();
+    }
+
+    // An empty document still needs a fragment to measure from.
+    pub fn ensure_fragment(&mut self) {
+        if self.map.is_empty() {
+            let empty = Fragment::new(Source::Original, 0, 0);
+            self.map.insert(0, empty);
+        }
+    }
+
+    pub fn insert_fragment(&mut self, byte_offset: usize, frag: Fragment) {
+        // Inserting past the end appends rather than leaving a gap.
+        let byte_offset = byte_offset.min(self.byte_length());
+        let inserted = frag.byte_length;
+        let changes = self.get_changes_for_insertion(byte_offset, frag);
+        self.record(byte_offset, &changes, inserted);
+
+        // Apply changes backwards to avoid overwriting fragments.
+        for change in changes.iter().rev() {
+            self.apply_change(change);
+        }
+    }
+
+    // Logs a planned edit, counting the deleted bytes from the plan itself.
+    fn record(&mut self, start: usize, changes: &[FragmentUpdate], inserted: usize) {
+        let deleted = changes
+            .iter()
+            .map(|change| self.calc_deleted_bytes(&change.operation))
+            .sum();
+
+        self.changes.record(Change {
+            start,
+            deleted,
+            inserted,
+        });
+    }
+
+    // Copies the fragments covering a byte range, trimmed to its edges.
+    pub fn slice_fragments(&self, range: &Range<usize>) -> Vec<Fragment> {
+        if range.start >= range.end {
+            return vec![];
+        }
+
+        let (first_key, _) = self
+            .map
+            .range(..=range.start)
+            .next_back()
+            .expect("Empty fragment set");
+
+        return self
+            .map
+            .range(*first_key..range.end)
+            .map(|(key, frag)| {
+                let start = range.start.max(*key) - key;
+                let end = range.end.min(key + frag.byte_length) - key;
+
+                Fragment::new(frag.source.clone(), frag.byte_offset + start, end - start)
+            })
+            .collect();
+    }
+
+    // Find all fragments representing the given byte range.
+    pub fn find_affected_fragments(&self, start_byte: &usize) -> Vec<Selector<'_>> {
+        let (start_offset, _) = self
+            .map
+            .range(..=start_byte)
+            .next_back()
+            .expect("Empty fragment set");
+
+        return self.map.range(start_offset..).collect();
+    }
+
+    // Handles 4 cases:
+    // 1. `fr>ag` Deletion ends on fragment
+    // 2. `fr<ag` Deletion begins on fragment
+    // 3. `f<ra>g` Deletion exists in fragment
+    // 4. `<prev|frag|next>` Deletion covers fragment
+    fn get_operation_for_fragment(&self, ranges: DeletionRange) -> FragmentUpdate {
+        // Deletion covers the entire fragment.
+        if ranges.deletion.start <= ranges.fragment.start
+            && ranges.deletion.end >= ranges.fragment.end
+        {
+            let Range { start, end } = ranges.fragment;
+            return FragmentUpdate {
+                operation: FragmentOperation::Delete(end - start),
+                move_to: ranges.fragment.start,
+                key: ranges.fragment.start,
+            };
+        }
+
+        // Deletion exists entirely within fragment.
+        if ranges.deletion.start > ranges.fragment.start
+            && ranges.deletion.end < ranges.fragment.end
+        {
+            let Range { start, end } = ranges.deletion;
+
+            return FragmentUpdate {
+                operation: FragmentOperation::Split(start, end),
+                move_to: ranges.fragment.start,
+                key: ranges.fragment.start,
+            };
+        }
+
+        // Deletion partially intersects with fragment.
+        let mut trim_start = 0;
+        let mut trim_end = 0;
+
+        let Range {
+            start: delete_start,
+            end: delete_end,
+        } = ranges.deletion;
+
+        let Range {
+            start: frag_start,
+            end: frag_end,
+        } = ranges.fragment;
+
+        // If deletion begins in the fragment, trim from the end.
+        if delete_start > frag_start && delete_start < frag_end {
+            trim_end = frag_end - delete_start;
+        }
+
+        // If deletion ends in the fragment, trim from the start.
+        if delete_end < frag_end && delete_end > frag_start {
+            trim_start = delete_end - frag_start;
+        }
+
+        let operation = match (trim_start, trim_end) {
+            (0, 0) => FragmentOperation::None,
+            (start, end) => FragmentOperation::Trim(start, end),
+        };
+
+        return FragmentUpdate {
+            move_to: ranges.fragment.start,
+            key: ranges.fragment.start,
+            operation,
+        };
+    }
+
+    fn calc_deleted_bytes(&self, op: &FragmentOperation) -> usize {
+        match op {
+            FragmentOperation::Split(start, end) => *end - *start,
+            FragmentOperation::Replace(start, end, _) => *end - *start,
+            FragmentOperation::Trim(start, end) => *start + *end,
+            FragmentOperation::Delete(bytes) => *bytes,
+            _ => 0,
+        }
+    }
+
+    fn get_changes_for_deletion(&self, deletion_range: &Range<usize>) -> Vec<FragmentUpdate> {
+        let frags = self.find_affected_fragments(&deletion_range.start);
+
+        let mut deleted_bytes = 0;
+        return frags
+            .iter()
+            .map(|(start_offset, frag)| {
+                let frag_end_offset = *start_offset + frag.byte_length;
+
+                self.get_operation_for_fragment(DeletionRange {
+                    fragment: **start_offset..frag_end_offset,
+                    deletion: deletion_range.clone(),
+                })
+            })
+            .map(|mut update| {
+                update.move_to = update.key - deleted_bytes;
+
+                let operation = &update.operation;
+                deleted_bytes += self.calc_deleted_bytes(operation);
+
+                return update;
+            })
+            .collect();
+    }
+
+    // Plans a deletion and an insertion in one pass. The fragment holding
+    // the start of the range takes the replacement; everything after it is
+    // trimmed, deleted or shifted by the difference in size.
+    fn get_changes_for_replacement(
+        &self,
+        range: &Range<usize>,
+        pieces: Vec<Fragment>,
+    ) -> Vec<FragmentUpdate> {
+        let frags = self.find_affected_fragments(&range.start);
+        let inserted_bytes: usize = pieces.iter().map(|frag| frag.byte_length).sum();
+        let mut pieces = Some(pieces);
+
+        let mut deleted_bytes = 0;
+        return frags
+            .iter()
+            .enumerate()
+            .map(|(idx, (key, frag))| {
+                let frag_end_offset = **key + frag.byte_length;
+
+                let mut update = self.get_operation_for_fragment(DeletionRange {
+                    fragment: **key..frag_end_offset,
+                    deletion: range.clone(),
+                });
+
+                update.operation = match (idx, pieces.take()) {
+                    (0, Some(pieces)) => {
+                        let resume = range.end.min(frag_end_offset);
+                        FragmentOperation::Replace(range.start, resume, pieces)
+                    }
+                    _ => update.operation,
+                };
+
+                update.move_to = match idx {
+                    0 => update.key,
+                    _ => update.key + inserted_bytes - deleted_bytes,
+                };
+
+                deleted_bytes += self.calc_deleted_bytes(&update.operation);
+
+                return update;
+            })
+            .collect();
+    }
+
+    fn get_changes_for_insertion(&self, start_byte: usize, ins: Fragment) -> Vec<FragmentUpdate> {
+        let frags = self.find_affected_fragments(&start_byte);
+
+        return frags
+            .iter()
+            .enumerate()
+            .map(|(idx, (key, _))| {
+                let (insertion_offset, operation) = match idx {
+                    0 => {
+                        let offset_from_start = start_byte - **key;
+                        let frag = FragmentOperation::Insert(offset_from_start, ins.clone());
+                        (0, frag)
+                    }
+                    _ => (ins.byte_length, FragmentOperation::None),
+                };
+
+                return FragmentUpdate {
+                    move_to: insertion_offset + **key,
+                    key: **key,
+                    operation,
+                };
+            })
+            .collect();
+    }
+
+    fn split_fragment(
+        &mut self,
+        change: &FragmentUpdate,
+        (stop, resume): (&usize, &usize),
+    ) -> Option<((usize, Fragment), (usize, Fragment))> {
+        let mut left = self.map.remove(&change.key)?;
+        let frag_offset_diff = resume - change.key;
+
+        let right_frag_byte_offset = left.byte_offset + frag_offset_diff;
+        let right_frag_byte_length = left.byte_length - frag_offset_diff;
+
+        let right = Fragment::new(
+            left.source.clone(),
+            right_frag_byte_offset,
+            right_frag_byte_length,
+        );
+
+        let left_length = stop - change.key;
+        left.resize(left.byte_offset, left_length);
+
+        return Some((
+            (change.move_to, left),
+            (change.move_to + left_length, right),
+        ));
+    }
+
+    fn trim_fragment(
+        &mut self,
+        change: &FragmentUpdate,
+        (start, end): (&usize, &usize),
+    ) -> Option<()> {
+        let mut frag = self.map.remove(&change.key)?;
+        let new_offset = frag.byte_offset + start;
+        let new_length = frag.byte_length - start - end;
+
+        frag.resize(new_offset, new_length);
+
+        self.map.insert(change.move_to, frag);
+        return Some(());
+    }
+
+    fn replace_in_fragment(
+        &mut self,
+        frag: Fragment,
+        change: &FragmentUpdate,
+        (stop, resume): (&usize, &usize),
+        pieces: &[Fragment],
+    ) {
+        let frag_end = change.key + frag.byte_length;
+
+        let left = Fragment::new(frag.source.clone(), frag.byte_offset, stop - change.key);
+        let right = Fragment::new(
+            frag.source.clone(),
+            frag.byte_offset + (resume - change.key),
+            frag_end - resume,
+        );
+
+        let mut offset = change.move_to;
+        for part in std::iter::once(&left).chain(pieces).chain(Some(&right)) {
+            // Empty edges are dropped rather than left as zero-width keys.
+            if part.byte_length == 0 {
+                continue;
+            }
+
+            self.map.insert(offset, part.clone());
+            offset += part.byte_length;
+        }
+    }
+
+    // Danger: fragment mutation and resizing zone.
+    // Remember not to confuse fragment offsets with derived offsets.
+    fn apply_change(&mut self, change: &FragmentUpdate) -> Option<()> {
+        match &change.operation {
+            FragmentOperation::None => {
+                if change.move_to != change.key {
+                    let frag = self.map.remove(&change.key)?;
+                    self.map.insert(change.move_to, frag);
+                }
+            }
+            FragmentOperation::Delete(_) => {
+                self.map.remove(&change.key)?;
+            }
+
+            FragmentOperation::Trim(start, end) => {
+                self.trim_fragment(change, (start, end))?;
+            }
+
+            FragmentOperation::Split(stop, resume) => {
+                let ((left_offset, left), (right_offset, right)) =
+                    self.split_fragment(change, (stop, resume))?;
+
+                self.map.insert(left_offset, left);
+                self.map.insert(right_offset, right);
+            }
+
+            FragmentOperation::Replace(stop, resume, pieces) => {
+                let frag = self.map.remove(&change.key)?;
+                self.replace_in_fragment(frag, change, (stop, resume), pieces);
+            }
+
+            FragmentOperation::Insert(at_byte, fragment) => {
+                let offset = *at_byte;
+                let insertion = fragment.clone();
+
+                self.apply_insert(change, (offset, insertion));
+            }
+        }
+
+        Some(())
+    }
+
+    fn apply_insert(
+        &mut self,
+        change: &FragmentUpdate,
+        (at_byte, insertion): (usize, Fragment),
+    ) -> Option<()> {
+        let offset = change.key + at_byte;
+        let frag = insertion.clone();
+        let target_frag = &self.map[&change.key];
+
+        // Appending.
+        if offset >= change.key + target_frag.byte_length {
+            self.map.insert(offset, frag);
+            return Some(());
+        }
+
+        // Prepending.
+        if offset == change.key {
+            let target_frag = self.map.remove(&change.key)?;
+            let new_offset = offset + insertion.byte_length;
+            self.map.insert(new_offset, target_frag);
+            self.map.insert(offset, insertion);
+            return Some(());
+        }
+
+        // Somewhere in the middle.
+        let split_change = FragmentUpdate {
+            operation: FragmentOperation::Split(offset, offset),
+            move_to: change.key,
+            key: change.key,
+        };
+
+        let ((left_offset, left), (right_offset, right)) =
+            self.split_fragment(&split_change, (&offset, &offset))?;
+
+        self.map.insert(left_offset, left);
+        self.map.insert(right_offset + insertion.byte_length, right);
+
+        self.map.insert(offset, insertion);
+
+        return Some(());
+    }
+}
+
+impl Deref for FragmentMap {
+    type Target = BTreeMap<usize, Fragment>;
+
+    fn deref(&self) -> &Self::Target {
+        return &self.map;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Document;
+
+    #[test]
+    fn test_find_affected_fragments() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(text.fragments.find_affected_fragments(&0).len(), 3);
+        assert_eq!(text.fragments.find_affected_fragments(&1).len(), 3);
+        assert_eq!(text.fragments.find_affected_fragments(&7).len(), 3);
+        assert_eq!(text.fragments.find_affected_fragments(&8).len(), 2);
+        assert_eq!(text.fragments.find_affected_fragments(&13).len(), 1);
+    }
+
+    #[test]
+    fn test_fragment_delete_at_end_operation() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(15..24)),
+            vec![FragmentUpdate {
+                operation: FragmentOperation::Trim(0, 9),
+                move_to: 13,
+                key: 13,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_fragment_delete_at_beginning() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(13..22)),
+            vec![FragmentUpdate {
+                operation: FragmentOperation::Trim(9, 0),
+                move_to: 13,
+                key: 13,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_fragment_delete_entire_fragment() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(13..24)),
+            vec![FragmentUpdate {
+                operation: FragmentOperation::Delete(11),
+                move_to: 13,
+                key: 13,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_delete_multiple_fragments() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(8..32)),
+            vec![
+                FragmentUpdate {
+                    operation: FragmentOperation::Delete(5),
+                    move_to: 8,
+                    key: 8,
+                },
+                FragmentUpdate {
+                    operation: FragmentOperation::Delete(11),
+                    move_to: 8,
+                    key: 13,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delete_middle_of_fragment() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(15..20)),
+            vec![FragmentUpdate {
+                operation: FragmentOperation::Split(15, 20),
+                move_to: 13,
+                key: 13,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_deletion_adjusts_later_elements() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(10..13)),
+            vec![
+                FragmentUpdate {
+                    operation: FragmentOperation::Trim(0, 3),
+                    move_to: 8,
+                    key: 8,
+                },
+                FragmentUpdate {
+                    operation: FragmentOperation::None,
+                    move_to: 10,
+                    key: 13,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deletion_split_adjusts_later_elements() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.get_changes_for_deletion(&(1..3)),
+            vec![
+                FragmentUpdate {
+                    operation: FragmentOperation::Split(1, 3),
+                    move_to: 0,
+                    key: 0,
+                },
+                FragmentUpdate {
+                    operation: FragmentOperation::None,
+                    move_to: 6,
+                    key: 8,
+                },
+                FragmentUpdate {
+                    operation: FragmentOperation::None,
+                    move_to: 11,
+                    key: 13,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_appending_insert_fragment_operations() {
+        let mut text = Document::from("hello");
+        let insert = text.create_insertion_fragment(" world!");
+
+        assert_eq!(
+            text.fragments.get_changes_for_insertion(6, insert.clone()),
+            vec![FragmentUpdate {
+                operation: FragmentOperation::Insert(6, insert),
+                move_to: 0,
+                key: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_insertions_adjust_later_elements() {
+        let mut text = Document::new();
+        text.insert(0, "original ");
+        text.insert(9, "insertions");
+        let insert = text.create_insertion_fragment("with ");
+
+        assert_eq!(
+            text.fragments.get_changes_for_insertion(8, insert.clone()),
+            vec![
+                FragmentUpdate {
+                    operation: FragmentOperation::Insert(8, insert.clone()),
+                    move_to: 0,
+                    key: 0,
+                },
+                FragmentUpdate {
+                    operation: FragmentOperation::None,
+                    move_to: 9 + insert.byte_length,
+                    key: 9,
+                }
+            ]
+        )
+    }
+
+    #[test]
+    fn test_slice_fragments() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        assert_eq!(
+            text.fragments.slice_fragments(&(6..15)),
+            vec![
+                Fragment::new(Source::Original, 6, 2),
+                Fragment::of_insertion(0, 5),
+                Fragment::of_insertion(5, 2),
+            ]
+        );
+    }
+    #[test]
+    fn test_replacement_operations() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+        let insert = text.create_insertion_fragment("out");
+
+        assert_eq!(
+            text.fragments
+                .get_changes_for_replacement(&(10..15), vec![insert.clone()]),
+            vec![
+                FragmentUpdate {
+                    operation: FragmentOperation::Replace(10, 13, vec![insert]),
+                    move_to: 8,
+                    key: 8,
+                },
+                FragmentUpdate {
+                    operation: FragmentOperation::Trim(2, 0),
+                    move_to: 13,
+                    key: 13,
+                }
+            ]
+        );
+    }
+}
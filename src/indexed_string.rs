@@ -1,46 +1,95 @@
 use std::collections::BTreeSet;
 use std::fmt;
-use std::ops::Bound::Included;
+use std::ops::{Bound::Included, Range};
+
+// Characters that can end a line, depending on configuration.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Terminator {
+    LineFeed,
+    CarriageReturn,
+    LineSeparator,
+    ParagraphSeparator,
+}
 
+#[derive(Clone)]
 pub struct IndexedString {
     linebreaks: BTreeSet<usize>,
+    carriage_returns: BTreeSet<usize>,
+    // Unicode line and paragraph separators, by their first byte.
+    separators: BTreeSet<usize>,
     source: String,
 }
 
 static LINE_FEED: u8 = 10; // '\n'
+static CARRIAGE_RETURN: u8 = 13; // '\r'
 
-impl IndexedString {
-    fn find_linebreaks(source: &str, byte_offset: usize) -> Vec<usize> {
-        let mut linebreaks = vec![];
+// U+2028 and U+2029 encode as E2 80 A8 and E2 80 A9.
+static SEPARATOR_PREFIX: [u8; 2] = [0xE2, 0x80];
+static LINE_SEPARATOR_SUFFIX: u8 = 0xA8;
+static PARAGRAPH_SEPARATOR_SUFFIX: u8 = 0xA9;
 
-        for (index, character) in source.bytes().enumerate() {
-            if character != LINE_FEED {
+impl IndexedString {
+    fn find_terminators(source: &str, byte_offset: usize) -> Vec<(usize, Terminator)> {
+        let bytes = source.as_bytes();
+        let mut terminators = vec![];
+
+        for (index, character) in bytes.iter().enumerate() {
+            let terminator = if *character == LINE_FEED {
+                Terminator::LineFeed
+            } else if *character == CARRIAGE_RETURN {
+                Terminator::CarriageReturn
+            } else if bytes[index..].starts_with(&SEPARATOR_PREFIX) && index + 2 < bytes.len() {
+                match bytes[index + 2] {
+                    suffix if suffix == LINE_SEPARATOR_SUFFIX => Terminator::LineSeparator,
+                    suffix if suffix == PARAGRAPH_SEPARATOR_SUFFIX => {
+                        Terminator::ParagraphSeparator
+                    }
+                    _ => continue,
+                }
+            } else {
                 continue;
-            }
+            };
 
-            linebreaks.push(index + byte_offset);
+            terminators.push((index + byte_offset, terminator));
         }
 
-        linebreaks
+        terminators
     }
 
-    fn index_linebreaks(&mut self) {
-        for linebreak in IndexedString::find_linebreaks(&self.source[..], 0) {
-            self.linebreaks.insert(linebreak);
+    fn index_terminators(&mut self, terminators: Vec<(usize, Terminator)>) {
+        for (byte_index, terminator) in terminators {
+            let index = match terminator {
+                Terminator::LineFeed => &mut self.linebreaks,
+                Terminator::CarriageReturn => &mut self.carriage_returns,
+                _ => &mut self.separators,
+            };
+
+            index.insert(byte_index);
         }
     }
 
+    fn index_linebreaks(&mut self) {
+        let terminators = IndexedString::find_terminators(&self.source[..], 0);
+        self.index_terminators(terminators);
+    }
+
     pub fn new() -> Self {
         IndexedString {
             linebreaks: BTreeSet::new(),
+            carriage_returns: BTreeSet::new(),
+            separators: BTreeSet::new(),
             source: String::new(),
         }
     }
 
     pub fn from(source: &str) -> Self {
+        return IndexedString::from_owned(source.to_owned());
+    }
+
+    pub fn from_owned(source: String) -> Self {
         let mut text = IndexedString {
-            linebreaks: BTreeSet::new(),
-            source: source.to_owned(),
+            source,
+            ..IndexedString::new()
         };
 
         text.index_linebreaks();
@@ -49,11 +98,8 @@ impl IndexedString {
     }
 
     pub fn append(&mut self, text: &str) {
-        let bytes = IndexedString::find_linebreaks(text, self.source.len());
-
-        for byte_index in bytes {
-            self.linebreaks.insert(byte_index);
-        }
+        let terminators = IndexedString::find_terminators(text, self.source.len());
+        self.index_terminators(terminators);
 
         self.source += text;
     }
@@ -62,12 +108,57 @@ impl IndexedString {
         self.source.len()
     }
 
+    pub fn slice(&self, range: Range<usize>) -> &str {
+        &self.source[range]
+    }
+
+    pub fn slice_bytes(&self, range: Range<usize>) -> &[u8] {
+        &self.source.as_bytes()[range]
+    }
+
+    pub fn is_char_boundary(&self, byte_offset: usize) -> bool {
+        self.source.is_char_boundary(byte_offset)
+    }
+
+    // Compares the linebreak caches against a fresh scan of the source.
+    pub fn has_consistent_linebreaks(&self) -> bool {
+        let mut expected = IndexedString::new();
+        expected.index_terminators(IndexedString::find_terminators(&self.source, 0));
+
+        self.linebreaks == expected.linebreaks
+            && self.carriage_returns == expected.carriage_returns
+            && self.separators == expected.separators
+    }
+
+    // Every line terminator in a byte range, relative to its start.
+    pub fn select_terminators(&self, range: Range<usize>) -> Vec<(usize, Terminator)> {
+        let start = range.start;
+        let line_feeds = self.linebreaks.range(range.clone());
+        let carriage_returns = self.carriage_returns.range(range.clone());
+
+        let mut terminators: Vec<(usize, Terminator)> = line_feeds
+            .map(|byte| (byte - start, Terminator::LineFeed))
+            .chain(carriage_returns.map(|byte| (byte - start, Terminator::CarriageReturn)))
+            .chain(self.separators.range(range).map(|byte| {
+                let terminator = match self.source.as_bytes()[byte + 2] {
+                    suffix if suffix == LINE_SEPARATOR_SUFFIX => Terminator::LineSeparator,
+                    _ => Terminator::ParagraphSeparator,
+                };
+
+                (byte - start, terminator)
+            }))
+            .collect();
+
+        terminators.sort_unstable();
+
+        return terminators;
+    }
+
     #[allow(dead_code)]
     pub fn select_relative_linebreaks(&self, start: usize, end: usize) -> Vec<usize> {
         self.linebreaks
             .range((Included(start), Included(end)))
-            .enumerate()
-            .map(|(_, linebreak)| (linebreak - start).clone())
+            .map(|linebreak| linebreak - start)
             .collect()
     }
 }
@@ -86,13 +177,13 @@ mod tests {
         text.linebreaks.into_iter().collect()
     }
 
-    fn get_first_linebreak<'a>(text: IndexedString) -> usize {
+    fn get_first_linebreak(text: IndexedString) -> usize {
         let linebreaks = get_linebreaks(text);
 
         return linebreaks
             .first()
-            .expect("Linebreaks set was empty.")
-            .clone();
+            .copied()
+            .expect("Linebreaks set was empty.");
     }
 
     #[test]
@@ -156,7 +247,7 @@ mod tests {
 
         let text = IndexedString::from(&source);
 
-        let expected_offset = elf_emoji.bytes().len();
+        let expected_offset = elf_emoji.len();
         assert_eq!(get_first_linebreak(text), expected_offset);
     }
 
@@ -184,6 +275,13 @@ mod tests {
         assert_eq!(text.len(), text.source.len());
     }
 
+    #[test]
+    fn test_slice() {
+        let text = IndexedString::from("first second");
+
+        assert_eq!(text.slice(6..12), "second");
+    }
+
     #[test]
     fn test_to_string() {
         let text = IndexedString::from("value");
@@ -191,6 +289,45 @@ mod tests {
         assert_eq!(text.to_string(), "value".to_owned());
     }
 
+    #[test]
+    fn test_consistent_linebreaks() {
+        let mut text = IndexedString::from("first\nsecond");
+        text.append("\nthird");
+        assert!(text.has_consistent_linebreaks());
+
+        text.linebreaks.insert(2);
+        assert!(!text.has_consistent_linebreaks());
+    }
+
+    #[test]
+    fn test_terminator_indexing() {
+        let mut text = IndexedString::from("a\r\nb\rc\u{2028}");
+        text.append("d\u{2029}\n");
+
+        assert_eq!(
+            text.select_terminators(0..text.len()),
+            vec![
+                (1, Terminator::CarriageReturn),
+                (2, Terminator::LineFeed),
+                (4, Terminator::CarriageReturn),
+                (6, Terminator::LineSeparator),
+                (10, Terminator::ParagraphSeparator),
+                (13, Terminator::LineFeed),
+            ]
+        );
+        assert!(text.has_consistent_linebreaks());
+    }
+
+    #[test]
+    fn test_relative_terminators() {
+        let text = IndexedString::from("ab\r\ncd\n");
+
+        assert_eq!(
+            text.select_terminators(3..7),
+            vec![(0, Terminator::LineFeed), (3, Terminator::LineFeed)]
+        );
+    }
+
     #[test]
     fn test_linebreak_indexing() {
         let text = IndexedString::from("first\nsecond\nthird\nfourth\nfifth");
@@ -0,0 +1,224 @@
+use super::fragment::Source;
+use super::fragment_map::FragmentMap;
+use super::Document;
+use std::{error::Error, fmt};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum InvariantViolation {
+    // The fragment map has no entries, not even an empty one.
+    NoFragments,
+    // A fragment key doesn't line up with the end of the previous fragment.
+    // (key, expected_key)
+    Discontiguous(usize, usize),
+    // A zero-length fragment in a non-empty document.
+    // (key)
+    EmptyFragment(usize),
+    // A fragment reaches past the end of its buffer.
+    // (key, fragment_end, buffer_length)
+    OutOfBounds(usize, usize, usize),
+    // A fragment starts or ends inside a UTF-8 sequence.
+    // (key, buffer_byte)
+    SplitsCharacter(usize, usize),
+    // The buffer's linebreak cache doesn't match its text.
+    StaleLinebreaks(Source),
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            InvariantViolation::NoFragments => write!(fmt, "Document has no fragments"),
+            InvariantViolation::Discontiguous(key, expected) => {
+                write!(fmt, "Fragment at {} should start at byte {}", key, expected)
+            }
+            InvariantViolation::EmptyFragment(key) => {
+                write!(fmt, "Fragment at {} is empty", key)
+            }
+            InvariantViolation::OutOfBounds(key, end, length) => write!(
+                fmt,
+                "Fragment at {} ends at byte {}, but its buffer is {} bytes",
+                key, end, length
+            ),
+            InvariantViolation::SplitsCharacter(key, byte) => write!(
+                fmt,
+                "Fragment at {} splits a character at buffer byte {}",
+                key, byte
+            ),
+            InvariantViolation::StaleLinebreaks(source) => {
+                write!(fmt, "Linebreak index for {:?} is stale", source)
+            }
+        };
+    }
+}
+
+impl Error for InvariantViolation {}
+
+impl Document {
+    // Verifies the fragment map is a gapless, in-bounds cover of the text.
+    // Meant for debugging `apply_change` and friends, not for hot paths.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        check_fragments(&self.fragments, |source| self.get_buffer(source).len())?;
+
+        for (key, frag) in self.fragments.iter() {
+            let source = self.get_fragment_source(frag);
+
+            for byte in [frag.byte_offset, frag.byte_offset + frag.byte_length].iter() {
+                if !source.is_char_boundary(*byte) {
+                    return Err(InvariantViolation::SplitsCharacter(*key, *byte));
+                }
+            }
+        }
+
+        if !self.original.has_consistent_linebreaks() {
+            return Err(InvariantViolation::StaleLinebreaks(Source::Original));
+        }
+
+        if !self.insertions.has_consistent_linebreaks() {
+            return Err(InvariantViolation::StaleLinebreaks(Source::Insertion));
+        }
+
+        for (id, buffer) in self.buffers.iter() {
+            if !buffer.has_consistent_linebreaks() {
+                return Err(InvariantViolation::StaleLinebreaks(Source::Buffer(id)));
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Runs after every public edit. The invariant checks are compiled out
+    // unless the `check-invariants` feature is on in a debug build.
+    pub(crate) fn after_edit(&mut self) {
+        #[cfg(all(debug_assertions, feature = "check-invariants"))]
+        {
+            if let Err(violation) = self.check_invariants() {
+                panic!("Piece table invariant violated: {}", violation);
+            }
+        }
+
+        self.write_journal();
+    }
+}
+
+// The checks that hold for any piece table, whatever its buffers contain.
+// `buffer_length` gives the length of the buffer behind a source.
+pub(crate) fn check_fragments(
+    fragments: &FragmentMap,
+    buffer_length: impl Fn(&Source) -> usize,
+) -> Result<(), InvariantViolation> {
+    if fragments.is_empty() {
+        return Err(InvariantViolation::NoFragments);
+    }
+
+    let mut expected_key = 0;
+    let is_empty_document = fragments.len() == 1 && fragments.byte_length() == 0;
+
+    for (key, frag) in fragments.iter() {
+        if *key != expected_key {
+            return Err(InvariantViolation::Discontiguous(*key, expected_key));
+        }
+
+        if frag.byte_length == 0 && !is_empty_document {
+            return Err(InvariantViolation::EmptyFragment(*key));
+        }
+
+        let length = buffer_length(&frag.source);
+        let frag_end = frag.byte_offset + frag.byte_length;
+
+        if frag_end > length {
+            return Err(InvariantViolation::OutOfBounds(*key, frag_end, length));
+        }
+
+        expected_key += frag.byte_length;
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::Fragment;
+
+    #[test]
+    fn test_new_document_is_valid() {
+        assert_eq!(Document::new().check_invariants(), Ok(()));
+        assert_eq!(Document::from("text").check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_edits_preserve_invariants() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+        text.insert(0, "the ");
+        text.delete(&(5..7));
+        text.insert(10, "-");
+        text.delete(&(2..20));
+
+        assert_eq!(text.to_string(), "thertions");
+        assert_eq!(text.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_deleting_everything_is_valid() {
+        let mut text = Document::from("text");
+        text.delete(&(0..4));
+
+        assert_eq!(text.len(), 0);
+        assert_eq!(text.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_detects_gaps() {
+        let mut text = Document::from("text");
+        let frag = text.fragments.map_mut().remove(&0).unwrap();
+        text.fragments
+            .map_mut()
+            .insert(0, Fragment::new(Source::Original, 0, 2));
+        text.fragments.map_mut().insert(3, frag);
+
+        assert_eq!(
+            text.check_invariants(),
+            Err(InvariantViolation::Discontiguous(3, 2))
+        );
+    }
+
+    #[test]
+    fn test_detects_empty_fragments() {
+        let mut text = Document::from("text");
+        text.fragments
+            .map_mut()
+            .insert(4, Fragment::of_insertion(0, 0));
+
+        assert_eq!(
+            text.check_invariants(),
+            Err(InvariantViolation::EmptyFragment(4))
+        );
+    }
+
+    #[test]
+    fn test_detects_out_of_bounds_fragments() {
+        let mut text = Document::from("text");
+        text.fragments
+            .map_mut()
+            .insert(0, Fragment::new(Source::Original, 2, 4));
+
+        assert_eq!(
+            text.check_invariants(),
+            Err(InvariantViolation::OutOfBounds(0, 6, 4))
+        );
+    }
+
+    #[test]
+    fn test_detects_split_characters() {
+        let mut text = Document::from("é");
+        text.fragments
+            .map_mut()
+            .insert(0, Fragment::new(Source::Original, 0, 1));
+
+        assert_eq!(
+            text.check_invariants(),
+            Err(InvariantViolation::SplitsCharacter(0, 1))
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use super::crc32::crc32;
+use super::fragment::Source;
+use super::{Change, Document};
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::{error::Error, fmt};
+
+// A journal is a header naming the original text by length and checksum,
+// as in a fast save, followed by one record per edit.
+//
+// Header: magic, format version (u16), original length (u64), original
+// CRC-32 (u32), CRC-32 of the preceding header bytes (u32).
+//
+// Record: record magic, payload length (u64), payload, CRC-32 of the payload
+// (u32). The payload is the edit's start (u64), how many bytes it deleted
+// (u64) and the text it inserted. The record magic lets recovery find the
+// records after a damaged one without checksumming at every offset.
+//
+// Integers are little-endian.
+const MAGIC: &[u8; 4] = b"PTJL";
+const RECORD_MAGIC: &[u8; 4] = b"PTJR";
+const FORMAT_VERSION: u16 = 2;
+const HEADER_LENGTH: usize = 22;
+// The record magic and payload length.
+const RECORD_HEADER_LENGTH: usize = 12;
+
+#[derive(Debug)]
+pub enum RecoveryError {
+    Io(io::Error),
+    // The file doesn't start with the journal magic bytes.
+    NotAJournal,
+    // Written by a newer version of the format.
+    // (format_version)
+    UnsupportedVersion(u16),
+    // The original text isn't the one the journal was started against.
+    OriginalChanged,
+    // A record other than the last is damaged or doesn't fit the text.
+    // (what_was_wrong)
+    Corrupt(&'static str),
+}
+
+// Appends every edit of a document to a file so it can be recovered after a
+// crash. See `Document::start_journal`.
+pub struct Journal {
+    file: File,
+    // Sync to disk after this many records. Zero leaves it to the OS and
+    // `Document::sync_journal`.
+    sync_every: usize,
+    unsynced: usize,
+    // The document version and length the file has caught up with.
+    version: u64,
+    length: usize,
+    // The first write that failed. Nothing more is written after it.
+    error: Option<io::Error>,
+}
+
+impl fmt::Display for RecoveryError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            RecoveryError::Io(error) => write!(fmt, "Failed to read journal: {}", error),
+            RecoveryError::NotAJournal => write!(fmt, "Not a journal file"),
+            RecoveryError::UnsupportedVersion(version) => {
+                write!(fmt, "Unsupported journal format version {}", version)
+            }
+            RecoveryError::OriginalChanged => {
+                write!(
+                    fmt,
+                    "The original file has changed since the journal was started"
+                )
+            }
+            RecoveryError::Corrupt(reason) => write!(fmt, "Journal is corrupt: {}", reason),
+        };
+    }
+}
+
+impl Error for RecoveryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            RecoveryError::Io(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<io::Error> for RecoveryError {
+    fn from(error: io::Error) -> Self {
+        return RecoveryError::Io(error);
+    }
+}
+
+impl Journal {
+    fn write_record(&mut self, start: usize, deleted: usize, text: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut payload = Vec::with_capacity(text.len() + 16);
+        payload.extend_from_slice(&(start as u64).to_le_bytes());
+        payload.extend_from_slice(&(deleted as u64).to_le_bytes());
+        payload.extend_from_slice(text);
+
+        // One write per record, so a crash tears at most the last one.
+        let mut record = Vec::with_capacity(payload.len() + RECORD_HEADER_LENGTH + 4);
+        record.extend_from_slice(RECORD_MAGIC);
+        record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+
+        let mut result = self.file.write_all(&record);
+        self.unsynced += 1;
+
+        if result.is_ok() && self.sync_every > 0 && self.unsynced >= self.sync_every {
+            result = self.file.sync_data();
+            self.unsynced = 0;
+        }
+
+        self.error = result.err();
+    }
+}
+
+impl Document {
+    // Starts recording edits to `path`, replacing whatever was there. The
+    // file is synced to disk after every `sync_every` edits; zero leaves
+    // syncing to `sync_journal`. Edits already made are recorded first.
+    pub fn start_journal<P: AsRef<Path>>(&mut self, path: P, sync_every: usize) -> io::Result<()> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.original.len() as u64).to_le_bytes());
+        header.extend_from_slice(&self.original_checksum().to_le_bytes());
+        header.extend_from_slice(&crc32(&header).to_le_bytes());
+
+        let mut file = File::create(path)?;
+        file.write_all(&header)?;
+
+        let mut journal = Journal {
+            file,
+            sync_every,
+            unsynced: 0,
+            version: self.version(),
+            length: self.len(),
+            error: None,
+        };
+
+        let original = self.original.len();
+        let unedited = self.fragments.len() == 1
+            && self.fragments.values().all(|frag| {
+                frag.byte_offset == 0
+                    && frag.byte_length == original
+                    && frag.source == Source::Original
+            });
+
+        if !unedited {
+            let text = self.to_string();
+            journal.write_record(0, original, text.as_bytes());
+        }
+
+        journal.file.sync_data()?;
+        journal.unsynced = 0;
+
+        if let Some(error) = journal.error.take() {
+            return Err(error);
+        }
+
+        self.journal = Some(journal);
+
+        return Ok(());
+    }
+
+    // Syncs the journal to disk. Also reports the first write that failed
+    // since journaling started, after which the journal is incomplete.
+    pub fn sync_journal(&mut self) -> io::Result<()> {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+
+        if let Some(error) = journal.error.take() {
+            self.journal = None;
+            return Err(error);
+        }
+
+        journal.unsynced = 0;
+
+        return journal.file.sync_data();
+    }
+
+    // Stops journaling, e.g. after a save. Deleting the file is up to the
+    // caller.
+    pub fn stop_journal(&mut self) -> io::Result<()> {
+        let result = self.sync_journal();
+        self.journal = None;
+
+        return result;
+    }
+
+    // Rebuilds a document from its original file and a journal. A final
+    // record cut short by a crash is ignored.
+    pub fn recover<P: AsRef<Path>, Q: AsRef<Path>>(
+        original_path: P,
+        journal_path: Q,
+    ) -> Result<Document, RecoveryError> {
+        let original = Document::from_bytes(&fs::read(original_path)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+        return Document::recover_from(original, File::open(journal_path)?);
+    }
+
+    // Like `recover`, for an original that's already loaded, e.g. with a
+    // specific encoding. It must be unedited. The journal is read one record
+    // at a time.
+    pub fn recover_from<R: Read>(
+        original: Document,
+        mut journal: R,
+    ) -> Result<Document, RecoveryError> {
+        let mut header = [0; HEADER_LENGTH];
+
+        if read_up_to(&mut journal, &mut header)? < HEADER_LENGTH || &header[..4] != MAGIC {
+            return Err(RecoveryError::NotAJournal);
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let original_length = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let original_checksum = u32::from_le_bytes(header[14..18].try_into().unwrap());
+
+        if crc32(&header[..18]) != u32::from_le_bytes(header[18..22].try_into().unwrap()) {
+            return Err(RecoveryError::Corrupt("bad header checksum"));
+        }
+
+        if version != FORMAT_VERSION {
+            return Err(RecoveryError::UnsupportedVersion(version));
+        }
+
+        if original_length != original.original.len() as u64
+            || original_checksum != original.original_checksum()
+        {
+            return Err(RecoveryError::OriginalChanged);
+        }
+
+        let mut document = original;
+
+        // Stops at the end, or at a final record torn by a crash while it
+        // was being written.
+        while let Some(payload) = read_record(&mut journal)? {
+            let (start, deleted, text) = parse_payload(&payload)?;
+            let end = start
+                .checked_add(deleted)
+                .filter(|end| *end <= document.len())
+                .ok_or(RecoveryError::Corrupt("edit is out of bounds"))?;
+
+            if !document.is_char_boundary(start) || !document.is_char_boundary(end) {
+                return Err(RecoveryError::Corrupt("edit splits a character"));
+            }
+
+            document.replace(&(start..end), text);
+        }
+
+        return Ok(document);
+    }
+
+    // Writes the edits made since the journal last caught up. They're
+    // folded into one record covering everything they touched, which for a
+    // single edit is just that edit.
+    pub(crate) fn write_journal(&mut self) {
+        let mut journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        match self.changes_since(journal.version) {
+            Some(changes) if changes.is_empty() => {}
+            Some(changes) => {
+                let (start, deleted, end) = covering_edit(&changes);
+                let text: Vec<u8> = self.byte_chunks(&(start..end)).flatten().copied().collect();
+                journal.write_record(start, deleted, &text);
+            }
+            None => {
+                let text = self.to_string();
+                journal.write_record(0, journal.length, text.as_bytes());
+            }
+        }
+
+        journal.version = self.version();
+        journal.length = self.len();
+        self.journal = Some(journal);
+    }
+}
+
+// One edit with the same effect as a series of them: where it starts, how
+// many bytes it deletes and where its inserted text ends afterwards.
+fn covering_edit(changes: &[Change]) -> (usize, usize, usize) {
+    let mut span: Option<(usize, usize)> = None;
+    let mut growth: isize = 0;
+
+    for change in changes {
+        let shift = change.inserted as isize - change.deleted as isize;
+
+        span = Some(match span {
+            None => (change.start, change.start + change.inserted),
+            Some((start, end)) => (
+                start.min(change.start),
+                // Whatever follows the change moves with it.
+                ((change.start + change.deleted).max(end) as isize + shift) as usize,
+            ),
+        });
+        growth += shift;
+    }
+
+    let (start, end) = span.unwrap_or((0, 0));
+
+    return (start, (end as isize - growth) as usize - start, end);
+}
+
+// Fills as much of `buffer` as the reader has left, returning how much.
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    return Ok(filled);
+}
+
+// Reads the next record's payload, or returns `None` at the end of the file
+// or if the record was cut short or damaged by a crash. Only the last record
+// can be; damage anywhere else means the file is corrupt.
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, RecoveryError> {
+    let mut header = [0; RECORD_HEADER_LENGTH];
+    let filled = read_up_to(reader, &mut header)?;
+
+    if filled == 0 {
+        return Ok(None);
+    }
+
+    let mut record = header[..filled].to_vec();
+
+    let damage = if filled < RECORD_HEADER_LENGTH {
+        "record is truncated"
+    } else if &header[..4] != RECORD_MAGIC {
+        "bad record marker"
+    } else {
+        let length = u64::from_le_bytes(header[4..].try_into().unwrap());
+
+        // Read through `take` so a damaged length can't allocate a huge
+        // buffer up front.
+        reader
+            .by_ref()
+            .take(length.saturating_add(4))
+            .read_to_end(&mut record)?;
+
+        let payload_end = record.len().saturating_sub(4).max(RECORD_HEADER_LENGTH);
+
+        if (payload_end - RECORD_HEADER_LENGTH) as u64 != length {
+            "bad record length"
+        } else if crc32(&record[RECORD_HEADER_LENGTH..payload_end]).to_le_bytes()
+            != record[payload_end..]
+        {
+            "bad record checksum"
+        } else {
+            record.truncate(payload_end);
+            record.drain(..RECORD_HEADER_LENGTH);
+
+            return Ok(Some(record));
+        }
+    };
+
+    // A record torn by a crash has nothing after it, so an intact one
+    // further on means this one was damaged instead. Only the offsets where
+    // a record magic appears are checked.
+    reader.read_to_end(&mut record)?;
+
+    let intact_later = record
+        .windows(RECORD_MAGIC.len())
+        .enumerate()
+        .skip(1)
+        .any(|(at, bytes)| bytes == RECORD_MAGIC && is_intact_record(&record[at..]));
+
+    return match intact_later {
+        true => Err(RecoveryError::Corrupt(damage)),
+        false => Ok(None),
+    };
+}
+
+// Whether `bytes` starts with a whole record whose checksum matches. Every
+// payload holds at least a start and a deleted count.
+fn is_intact_record(bytes: &[u8]) -> bool {
+    let length = match bytes.get(4..RECORD_HEADER_LENGTH) {
+        Some(length) => u64::from_le_bytes(length.try_into().unwrap()),
+        None => return false,
+    };
+
+    let end = usize::try_from(length)
+        .ok()
+        .and_then(|length| length.checked_add(RECORD_HEADER_LENGTH + 4))
+        .filter(|end| *end <= bytes.len());
+
+    return match end {
+        Some(end) if length >= 16 => {
+            let (payload, checksum) =
+                bytes[RECORD_HEADER_LENGTH..end].split_at(end - RECORD_HEADER_LENGTH - 4);
+            crc32(payload).to_le_bytes() == checksum
+        }
+        _ => false,
+    };
+}
+
+fn parse_payload(payload: &[u8]) -> Result<(usize, usize, &str), RecoveryError> {
+    if payload.len() < 16 {
+        return Err(RecoveryError::Corrupt("record is truncated"));
+    }
+
+    let start = u64::from_le_bytes(payload[..8].try_into().unwrap()) as usize;
+    let deleted = u64::from_le_bytes(payload[8..16].try_into().unwrap()) as usize;
+    let text = std::str::from_utf8(&payload[16..])
+        .map_err(|_| RecoveryError::Corrupt("text isn't valid UTF-8"))?;
+
+    return Ok((start, deleted, text));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("journal-{}-{}", std::process::id(), name));
+    }
+
+    fn recover_text(original: &str, journal: &Path) -> Result<String, RecoveryError> {
+        let original = Document::from(original);
+        let document = Document::recover_from(original, File::open(journal)?)?;
+
+        return Ok(document.to_string());
+    }
+
+    #[test]
+    fn test_recovery_replays_edits() {
+        let path = temp_path("replay");
+        let mut text = Document::from("one two three");
+        text.start_journal(&path, 1).unwrap();
+
+        text.insert(3, ",");
+        text.delete(&(0..1));
+        text.replace(&(4..7), "deux");
+        text.move_range(&(0..3), text.len());
+        text.include(0, "ünïcode ".to_owned());
+        text.replace_all("e", "é");
+
+        assert_eq!(
+            recover_text("one two three", &path).unwrap(),
+            text.to_string()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_edits_before_the_journal() {
+        let path = temp_path("before");
+        let mut text = Document::from("text");
+        text.insert(0, "some ");
+        text.start_journal(&path, 0).unwrap();
+        text.insert(text.len(), "!");
+        text.stop_journal().unwrap();
+        text.insert(0, "not journaled ");
+
+        assert_eq!(recover_text("text", &path).unwrap(), "some text!");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_final_record_is_ignored() {
+        let path = temp_path("torn");
+        let mut text = Document::from("abc");
+        text.start_journal(&path, 1).unwrap();
+        text.insert(3, "d");
+        text.insert(4, "efgh");
+
+        let length = fs::metadata(&path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+
+        for cut in 1..(RECORD_HEADER_LENGTH as u64 + 16 + 4 + 4) {
+            file.set_len(length - cut).unwrap();
+            assert_eq!(recover_text("abc", &path).unwrap(), "abcd");
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_records() {
+        let path = temp_path("damaged");
+        let mut text = Document::from("abc");
+        text.start_journal(&path, 0).unwrap();
+        text.insert(3, "d");
+        text.insert(4, "e");
+        text.stop_journal().unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_LENGTH + RECORD_HEADER_LENGTH] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        // The damaged record isn't the last, so it wasn't torn by a crash.
+        assert!(matches!(
+            recover_text("abc", &path),
+            Err(RecoveryError::Corrupt(_))
+        ));
+        assert!(matches!(
+            recover_text("abd", &path),
+            Err(RecoveryError::OriginalChanged)
+        ));
+
+        fs::write(&path, b"something else entirely").unwrap();
+        assert!(matches!(
+            recover_text("abc", &path),
+            Err(RecoveryError::NotAJournal)
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_length_isnt_a_torn_record() {
+        let path = temp_path("length");
+        let mut text = Document::from("abc");
+        text.start_journal(&path, 0).unwrap();
+        text.insert(3, "d");
+        text.insert(4, "e");
+        text.insert(5, "f");
+        text.stop_journal().unwrap();
+
+        // The first record now claims to run past the end of the file.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_LENGTH + 10] = 0xFF;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            recover_text("abc", &path),
+            Err(RecoveryError::Corrupt(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_marker() {
+        let path = temp_path("marker");
+        let mut text = Document::from("abc");
+        text.start_journal(&path, 0).unwrap();
+        text.insert(3, "d");
+        text.insert(4, "e");
+        text.stop_journal().unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_LENGTH] = b'X';
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            recover_text("abc", &path),
+            Err(RecoveryError::Corrupt("bad record marker"))
+        ));
+
+        // Zeros where the last record should be, as a crash can leave.
+        let last = bytes.len() - (RECORD_HEADER_LENGTH + 16 + 1 + 4);
+        bytes[HEADER_LENGTH] = RECORD_MAGIC[0];
+        bytes[last..].iter_mut().for_each(|byte| *byte = 0);
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(recover_text("abc", &path).unwrap(), "abcd");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_paths() {
+        let original_path = temp_path("original");
+        let journal_path = temp_path("paths");
+        fs::write(&original_path, "first line\n").unwrap();
+
+        let mut text = Document::from_bytes(&fs::read(&original_path).unwrap()).unwrap();
+        text.start_journal(&journal_path, 1).unwrap();
+        text.insert(text.len(), "second line\n");
+
+        let recovered = Document::recover(&original_path, &journal_path).unwrap();
+        assert_eq!(recovered.to_string(), "first line\nsecond line\n");
+
+        fs::remove_file(&original_path).unwrap();
+        fs::remove_file(&journal_path).unwrap();
+    }
+}
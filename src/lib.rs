@@ -1,66 +1,100 @@
+use buffers::BufferRegistry;
 use fragment::{Fragment, Source};
+use fragment_map::FragmentMap;
 use indexed_string::IndexedString;
-use std::{collections::BTreeMap, fmt, ops::Range};
-
+use journal::Journal;
+use modified::SavePoint;
+use save::DiskState;
+use std::{fmt, ops::Range, sync::Arc};
+
+pub use byte_document::ByteDocument;
+pub use changes::Change;
+pub use clip::Clip;
+pub use compare::Granularity;
+pub use deleted::{Deleted, DeletedText};
+pub use diff::Hunk;
+pub use encoding::{Codec, DecodeError, EncodeError, Encoding};
+pub use fast_save::{FastSaveError, FastSaveState};
+pub use invariants::InvariantViolation;
+pub use journal::RecoveryError;
+pub use line_endings::{LineEnding, LinebreakMode};
+pub use reader::DocumentReader;
+pub use regex::{Captures, Regex, RegexError, RegexMatches};
+pub use replace::{Pattern, ReplaceError, Replacement};
+pub use save::SaveError;
+pub use search::{Matches, QueryMatches, SearchOptions, SearchQuery};
+pub use search_session::SearchSession;
+pub use stream::LoadError;
+
+mod buffers;
+mod byte_document;
+mod changes;
+mod clip;
+mod compare;
+mod crc32;
+mod deleted;
+mod diff;
+mod encoding;
+mod fast_save;
 mod fragment;
+mod fragment_map;
 mod indexed_string;
-
-type Selector<'a> = (&'a usize, &'a Fragment);
-
-#[derive(Debug)]
-struct DeletionRange {
-    fragment: Range<usize>,
-    deletion: Range<usize>,
-}
-
-#[derive(Debug, PartialEq)]
-enum FragmentOperation {
-    // Insert a fragment into another fragment.
-    // (byte_offset_from_start, Fragment)
-    Insert(usize, Fragment),
-    // Split the fragment into two parts.
-    // (split_at_byte, resume_at_byte)
-    Split(usize, usize),
-    // Remove fragment text from the beginning, end, or both.
-    // (trim_start_bytes, trim_end_bytes)
-    Trim(usize, usize),
-    // Remove the fragment. Metadata just for convenience.
-    // (deleted_byte_count)
-    Delete(usize),
-    // No change.
-    None,
-}
-
-#[derive(PartialEq, Debug)]
-struct FragmentUpdate {
-    operation: FragmentOperation,
-    move_to: usize,
-    key: usize,
-}
+mod invariants;
+mod journal;
+mod line_endings;
+mod modified;
+mod original;
+mod reader;
+mod regex;
+mod reload;
+mod replace;
+mod save;
+mod search;
+mod search_session;
+mod stream;
 
 pub struct Document {
-    fragments: BTreeMap<usize, Fragment>,
-    insertions: IndexedString,
-    original: IndexedString,
+    fragments: FragmentMap,
+    // Typed text, until the buffer is shared with a clip or another document.
+    insertions: Arc<IndexedString>,
+    // Where typed text goes: `insertions`, or once that is shared, a buffer
+    // of its own in `buffers`, so sharing never means copying.
+    typing: Source,
+    original: Arc<IndexedString>,
+    // Read-only text adopted from other documents, clips and files.
+    buffers: BufferRegistry,
+    // How the text is stored on disk.
+    encoding: Arc<dyn Codec + Send + Sync>,
+    bom: bool,
+    linebreak_mode: LinebreakMode,
+    // Crash recovery log of the edits, if enabled.
+    journal: Option<Journal>,
+    // The file the document was opened from or last saved to.
+    disk: Option<DiskState>,
+    // What `is_modified` compares against.
+    saved: SavePoint,
 }
 
 impl Document {
-    fn create_fragment_map(source: &IndexedString) -> BTreeMap<usize, Fragment> {
-        let mut fragments = BTreeMap::new();
-        let initial_fragment = Fragment::from_string(&source);
-
-        fragments.insert(0, initial_fragment);
-
-        return fragments;
+    pub fn from(text: &str) -> Self {
+        return Document::from_original(IndexedString::from(text));
     }
 
-    pub fn from(text: &str) -> Self {
-        let original = IndexedString::from(text);
+    fn from_original(original: IndexedString) -> Self {
+        let initial = Fragment::from_string(&original);
 
         Document {
-            fragments: Document::create_fragment_map(&original),
-            insertions: IndexedString::new(),
-            original,
+            fragments: FragmentMap::new(initial.clone()),
+            insertions: Arc::new(IndexedString::new()),
+            typing: Source::Insertion,
+            original: Arc::new(original),
+            buffers: BufferRegistry::new(),
+            encoding: Arc::new(Encoding::Utf8),
+            bom: false,
+            linebreak_mode: LinebreakMode::default(),
+            journal: None,
+            disk: None,
+            saved: SavePoint::new(0, vec![initial]),
         }
     }
 
@@ -68,328 +102,303 @@ impl Document {
         return Document::from("");
     }
 
-    pub fn len(&self) -> usize {
-        let (last_offset, last_fragment) = self
-            .fragments
-            .iter()
-            .rev()
-            .next()
-            .expect("Somehow the buffer text doesn't have a fragment.");
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
 
-        return last_offset + last_fragment.byte_length;
+    pub fn len(&self) -> usize {
+        return self.fragments.byte_length();
     }
 
     pub fn insert(&mut self, byte_offset: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+
         let frag = self.create_insertion_fragment(text);
-        let changes = self.get_changes_for_insertion(byte_offset, frag);
+        self.fragments.insert_fragment(byte_offset, frag);
 
-        // Apply changes backwards to avoid overwriting fragments.
-        for change in changes.iter().rev() {
-            self.apply_change(change);
-        }
+        self.after_edit();
     }
 
-    fn get_fragment_source(&self, fragment: &Fragment) -> &IndexedString {
-        return match fragment.source {
-            Source::Insertion => &self.insertions,
-            Source::Original => &self.original,
-        };
+    // Puts previously deleted text back without copying any bytes. The
+    // `Deleted` value must have come from this document.
+    pub fn reinsert(&mut self, byte_offset: usize, deleted: &Deleted) {
+        self.splice(&(byte_offset..byte_offset), deleted.fragments.clone());
+
+        self.after_edit();
     }
 
-    // Find all fragments representing the given byte range.
-    fn find_affected_fragments(&self, start_byte: &usize) -> Vec<Selector> {
-        let (start_offset, _) = self
-            .fragments
-            .range(..=start_byte)
-            .rev()
-            .next()
-            .expect("Empty fragment set");
+    // Deletes a range and inserts text in its place as a single edit.
+    pub fn replace(&mut self, range: &Range<usize>, text: &str) -> Deleted {
+        let pieces = match text.is_empty() {
+            true => vec![],
+            false => vec![self.create_insertion_fragment(text)],
+        };
 
-        return self.fragments.range(start_offset..).collect();
-    }
-
-    // Handles 4 cases:
-    // 1. `fr>ag` Deletion ends on fragment
-    // 2. `fr<ag` Deletion begins on fragment
-    // 3. `f<ra>g` Deletion exists in fragment
-    // 4. `<prev|frag|next>` Deletion covers fragment
-    fn get_operation_for_fragment(&self, ranges: DeletionRange) -> FragmentUpdate {
-        // Deletion covers the entire fragment.
-        if ranges.deletion.start <= ranges.fragment.start
-            && ranges.deletion.end >= ranges.fragment.end
-        {
-            let Range { start, end } = ranges.fragment;
-            return FragmentUpdate {
-                operation: FragmentOperation::Delete(end - start),
-                move_to: ranges.fragment.start,
-                key: ranges.fragment.start,
-            };
-        }
+        let deleted = self.splice(range, pieces);
+        self.after_edit();
 
-        // Deletion exists entirely within fragment.
-        if ranges.deletion.start > ranges.fragment.start
-            && ranges.deletion.end < ranges.fragment.end
-        {
-            let Range { start, end } = ranges.deletion;
-
-            return FragmentUpdate {
-                operation: FragmentOperation::Split(start, end),
-                move_to: ranges.fragment.start,
-                key: ranges.fragment.start,
-            };
-        }
+        return deleted;
+    }
 
-        // Deletion partially intersects with fragment.
-        let mut trim_start = 0;
-        let mut trim_end = 0;
+    // Overtype: replaces one character for every character inserted. Stops
+    // at a linebreak so typing past the end of a line extends it instead.
+    pub fn overwrite(&mut self, byte_offset: usize, text: &str) -> Deleted {
+        let start = byte_offset.min(self.len());
+        let mut remaining = text.chars().count();
+        let mut end = start;
 
-        let Range {
-            start: delete_start,
-            end: delete_end,
-        } = ranges.deletion;
+        'fragments: for frag in self.fragments.slice_fragments(&(start..self.len())) {
+            let slice = frag.get_slice(self.get_fragment_source(&frag));
 
-        let Range {
-            start: frag_start,
-            end: frag_end,
-        } = ranges.fragment;
+            for character in slice.chars() {
+                if remaining == 0 || character == '\n' {
+                    break 'fragments;
+                }
 
-        // If deletion begins in the fragment, trim from the end.
-        if delete_start > frag_start && delete_start < frag_end {
-            trim_end = frag_end - delete_start;
+                end += character.len_utf8();
+                remaining -= 1;
+            }
         }
 
-        // If deletion ends in the fragment, trim from the start.
-        if delete_end < frag_end && delete_end > frag_start {
-            trim_start = delete_end - frag_start;
-        }
+        return self.replace(&(start..end), text);
+    }
 
-        let operation = match (trim_start, trim_end) {
-            (0, 0) => FragmentOperation::None,
-            (start, end) => FragmentOperation::Trim(start, end),
-        };
+    // Copies a range to another offset by sharing its fragments, so no
+    // text bytes are duplicated. Returns where the copy landed.
+    pub fn duplicate_range(&mut self, range: &Range<usize>, to: usize) -> Range<usize> {
+        let to = to.min(self.len());
+        let pieces = self
+            .fragments
+            .slice_fragments(&(range.start..range.end.min(self.len())));
+        let copied: usize = pieces.iter().map(|frag| frag.byte_length).sum();
 
-        return FragmentUpdate {
-            move_to: ranges.fragment.start,
-            key: ranges.fragment.start,
-            operation,
-        };
-    }
+        self.splice(&(to..to), pieces);
+        self.after_edit();
 
-    fn calc_deleted_bytes(&self, op: &FragmentOperation) -> usize {
-        match op {
-            FragmentOperation::Split(start, end) => *end - *start,
-            FragmentOperation::Trim(start, end) => *start + *end,
-            FragmentOperation::Delete(bytes) => *bytes,
-            _ => 0,
-        }
+        return to..to + copied;
     }
 
-    fn get_changes_for_deletion(&self, deletion_range: &Range<usize>) -> Vec<FragmentUpdate> {
-        let frags = self.find_affected_fragments(&deletion_range.start);
+    // Moves a range to another offset, given in terms of the document before
+    // the move. Only fragments are rearranged. Returns the range's new home.
+    pub fn move_range(&mut self, range: &Range<usize>, to: usize) -> Range<usize> {
+        let range = range.start.min(self.len())..range.end.min(self.len());
+        let to = to.min(self.len());
 
-        let mut deleted_bytes = 0;
-        return frags
-            .iter()
-            .map(|(start_offset, frag)| {
-                let frag_end_offset = *start_offset + frag.byte_length;
+        // Moving text into itself is a no-op.
+        if to >= range.start && to <= range.end {
+            return range;
+        }
 
-                self.get_operation_for_fragment(DeletionRange {
-                    fragment: **start_offset..frag_end_offset,
-                    deletion: deletion_range.clone(),
-                })
-            })
-            .map(|mut update| {
-                update.move_to = update.key - deleted_bytes;
+        // One splice, so the move is a single change.
+        let pieces = self.fragments.slice_fragments(&range);
+        let replacements = match to > range.end {
+            true => vec![(range.clone(), vec![]), (to..to, pieces)],
+            false => vec![(to..to, pieces), (range.clone(), vec![])],
+        };
+        self.splice_replacements(replacements);
 
-                let operation = &update.operation;
-                deleted_bytes += self.calc_deleted_bytes(&operation);
+        return match to > range.end {
+            true => to - range.len()..to,
+            false => to..to + range.len(),
+        };
+    }
 
-                return update;
+    // Cuts the document in two. Both halves share the buffers, so no text is
+    // copied.
+    pub fn split_off(&mut self, at: usize) -> Document {
+        let at = at.min(self.len());
+        let tail = self.splice(&(at..self.len()), vec![]);
+        let mut other = Document::new();
+        other.set_encoding(Arc::clone(&self.encoding), self.bom);
+        other.set_linebreak_mode(self.linebreak_mode);
+
+        let pieces = tail
+            .fragments
+            .into_iter()
+            .map(|mut frag| {
+                frag.source = other.adopt_buffer(self.get_buffer(&frag.source));
+                frag
             })
             .collect();
-    }
 
-    fn create_insertion_fragment(&mut self, ins: &str) -> Fragment {
-        let offset = self.insertions.len();
-        self.insertions.append(ins);
+        // An edit like any other, so the new document's version and save
+        // point see the text arrive.
+        other.splice(&(0..0), pieces);
+        self.after_edit();
+        other.after_edit();
 
-        return Fragment::of_insertion(offset, ins.len());
+        return other;
     }
 
-    fn get_changes_for_insertion(&self, start_byte: usize, ins: Fragment) -> Vec<FragmentUpdate> {
-        let frags = self.find_affected_fragments(&start_byte);
+    // Moves another document's text onto the end of this one. Its buffers
+    // are adopted rather than copied.
+    pub fn append(&mut self, other: Document) {
+        let mut pieces = vec![];
 
-        return frags
-            .iter()
-            .enumerate()
-            .map(|(idx, (key, _))| {
-                let (insertion_offset, operation) = match idx {
-                    0 => {
-                        let offset_from_start = start_byte - **key;
-                        let frag = FragmentOperation::Insert(offset_from_start, ins.clone());
-                        (0, frag)
-                    }
-                    _ => (ins.byte_length, FragmentOperation::None),
-                };
-
-                return FragmentUpdate {
-                    move_to: insertion_offset + **key,
-                    key: **key,
-                    operation,
-                };
-            })
-            .collect();
-    }
+        for frag in other.fragments.values().filter(|frag| frag.byte_length > 0) {
+            let source = self.adopt_buffer(other.get_buffer(&frag.source));
+            pieces.push(Fragment::new(source, frag.byte_offset, frag.byte_length));
+        }
 
-    fn split_fragment(
-        &mut self,
-        change: &FragmentUpdate,
-        (stop, resume): (&usize, &usize),
-    ) -> Option<((usize, Fragment), (usize, Fragment))> {
-        let mut left = self.fragments.remove(&change.key)?;
-        let frag_offset_diff = resume - change.key;
+        let end = self.len();
+        self.splice(&(end..end), pieces);
+        self.after_edit();
+    }
 
-        let right_frag_byte_offset = left.byte_offset + frag_offset_diff;
-        let right_frag_byte_length = left.byte_length - frag_offset_diff;
+    fn get_buffer(&self, source: &Source) -> &Arc<IndexedString> {
+        return match *source {
+            Source::Insertion => &self.insertions,
+            Source::Original => &self.original,
+            Source::Buffer(id) => self.buffers.get(id),
+        };
+    }
 
-        let right = Fragment::new(
-            left.source.clone(),
-            right_frag_byte_offset,
-            right_frag_byte_length,
-        );
+    // Finds or registers a buffer from another document, returning how this
+    // document refers to it.
+    fn adopt_buffer(&mut self, buffer: &Arc<IndexedString>) -> Source {
+        if Arc::ptr_eq(buffer, &self.original) {
+            return Source::Original;
+        }
 
-        left.resize(left.byte_offset, stop - change.key);
+        if Arc::ptr_eq(buffer, &self.insertions) {
+            return Source::Insertion;
+        }
 
-        return Some(((change.move_to, left), (*resume, right)));
+        return Source::Buffer(self.buffers.register(buffer));
     }
 
-    fn trim_fragment(
-        &mut self,
-        change: &FragmentUpdate,
-        (start, end): (&usize, &usize),
-    ) -> Option<()> {
-        let mut frag = self.fragments.remove(&change.key)?;
-        let new_offset = frag.byte_offset + start;
-        let new_length = frag.byte_length - start - end;
+    // Inserts text as its own read-only buffer, e.g. for an "include file"
+    // command. The string is moved in, not copied.
+    pub fn include(&mut self, byte_offset: usize, text: String) {
+        if text.is_empty() {
+            return;
+        }
 
-        frag.resize(new_offset, new_length);
+        let buffer = Arc::new(IndexedString::from_owned(text));
+        let frag = Fragment::new(self.adopt_buffer(&buffer), 0, buffer.len());
 
-        self.fragments.insert(change.move_to, frag);
-        return Some(());
+        self.splice(&(byte_offset..byte_offset), vec![frag]);
+        self.after_edit();
     }
 
-    // Danger: fragment mutation and resizing zone.
-    // Remember not to confuse fragment offsets with derived offsets.
-    fn apply_change(&mut self, change: &FragmentUpdate) -> Option<()> {
-        match &change.operation {
-            FragmentOperation::None => {}
-            FragmentOperation::Delete(_) => {
-                self.fragments.remove(&change.key)?;
-            }
+    fn splice(&mut self, range: &Range<usize>, pieces: Vec<Fragment>) -> Deleted {
+        let start = range.start.min(self.len());
+        let range = start..range.end.max(start).min(self.len());
+        let removed = self.fragments.splice(&range, pieces);
 
-            FragmentOperation::Trim(start, end) => {
-                self.trim_fragment(change, (start, end))?;
-            }
+        return Deleted::new(range.start, removed);
+    }
 
-            FragmentOperation::Split(stop, resume) => {
-                let ((left_offset, left), (right_offset, right)) =
-                    self.split_fragment(&change, (stop, resume))?;
+    fn get_fragment_source(&self, fragment: &Fragment) -> &IndexedString {
+        return self.get_buffer(&fragment.source);
+    }
 
-                self.fragments.insert(left_offset, left);
-                self.fragments.insert(right_offset, right);
-            }
+    // Borrows the text in a byte range one fragment at a time, straight
+    // from the buffers.
+    pub fn chunks(&self, range: &Range<usize>) -> impl DoubleEndedIterator<Item = &str> + '_ {
+        return self
+            .chunk_ranges(range)
+            .map(|(source, range)| source.slice(range));
+    }
 
-            FragmentOperation::Insert(at_byte, fragment) => {
-                let offset = *at_byte;
-                let insertion = fragment.clone();
+    // Like `chunks`, but the range may start or end inside a character.
+    fn byte_chunks(&self, range: &Range<usize>) -> impl DoubleEndedIterator<Item = &[u8]> + '_ {
+        return self
+            .chunk_ranges(range)
+            .map(|(source, range)| source.slice_bytes(range));
+    }
 
-                self.apply_insert(&change, (offset, insertion));
-            }
-        }
+    // The buffer and buffer range behind each fragment in a byte range.
+    fn chunk_ranges(
+        &self,
+        range: &Range<usize>,
+    ) -> impl DoubleEndedIterator<Item = (&IndexedString, Range<usize>)> + '_ {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
 
-        Some(())
-    }
+        let (first_key, _) = self
+            .fragments
+            .range(..=start)
+            .next_back()
+            .expect("Empty fragment set");
 
-    fn apply_insert(
-        &mut self,
-        change: &FragmentUpdate,
-        (at_byte, insertion): (usize, Fragment),
-    ) -> Option<()> {
-        let offset = change.key + at_byte;
-        let frag = insertion.clone();
-        let target_frag = &self.fragments[&change.key];
+        return self
+            .fragments
+            .range(*first_key..end)
+            .map(move |(key, frag)| {
+                let source = self.get_fragment_source(frag);
+                let from = frag.byte_offset + start.max(*key) - key;
+                let to = frag.byte_offset + end.min(key + frag.byte_length) - key;
 
-        // Appending.
-        if offset >= change.key + target_frag.byte_length {
-            self.fragments.insert(offset, frag);
-            return Some(());
-        }
+                (source, from..to)
+            })
+            .filter(|(_, range)| !range.is_empty());
+    }
 
-        // Prepending.
-        if offset == change.key {
-            let target_frag = self.fragments.remove(&change.key)?;
-            let new_offset = offset + insertion.byte_length;
-            self.fragments.insert(new_offset, target_frag);
-            self.fragments.insert(offset, insertion);
-            return Some(());
+    fn create_insertion_fragment(&mut self, ins: &str) -> Fragment {
+        // Something else holds the buffer, so appending would copy it all.
+        // Start a new one; text already in the old one stays where it is.
+        if Arc::strong_count(self.get_buffer(&self.typing)) > 1 {
+            let id = self.buffers.register(&Arc::new(IndexedString::new()));
+            self.typing = Source::Buffer(id);
         }
 
-        // Somewhere in the middle.
-        let split_change = FragmentUpdate {
-            operation: FragmentOperation::Split(offset, offset),
-            move_to: change.key,
-            key: change.key,
+        let buffer = match self.typing {
+            Source::Buffer(id) => self.buffers.get_mut(id),
+            _ => &mut self.insertions,
         };
+        let offset = buffer.len();
+        Arc::make_mut(buffer).append(ins);
 
-        let ((left_offset, left), (right_offset, right)) =
-            self.split_fragment(&split_change, (&offset, &offset))?;
+        return Fragment::new(self.typing.clone(), offset, ins.len());
+    }
 
-        self.fragments.insert(left_offset, left);
-        self.fragments
-            .insert(right_offset + insertion.byte_length, right);
+    pub fn delete(&mut self, range: &Range<usize>) -> Deleted {
+        let range = &(range.start..range.end.min(self.len()));
+        let removed = self.fragments.slice_fragments(range);
 
-        self.fragments.insert(offset, insertion);
+        if !removed.is_empty() {
+            self.fragments.delete(range);
+            self.after_edit();
+        }
 
-        return Some(());
+        return Deleted::new(range.start, removed);
     }
+}
 
-    pub fn delete(&mut self, range: &Range<usize>) {
-        let changes = self.get_changes_for_deletion(&range);
-
-        for change in changes {
-            self.apply_change(&change);
-        }
+impl Default for Document {
+    fn default() -> Self {
+        return Document::new();
     }
 }
 
 impl fmt::Display for Document {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let result: String = self
-            .fragments
-            .iter()
-            .map(|(_, frag)| (frag, self.get_fragment_source(&frag)))
-            .map(|(frag, source)| frag.get_slice(&source))
-            .collect();
+        for chunk in self.chunks(&(0..self.len())) {
+            fmt.write_str(chunk)?;
+        }
 
-        return write!(fmt, "{}", result);
+        return Ok(());
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use fragment_map::Selector;
 
-    fn get_fragment_tuple(text: &Document, index: usize) -> Selector {
+    fn get_fragment_tuple(text: &Document, index: usize) -> Selector<'_> {
         return text
             .fragments
             .iter()
-            .take(index + 1)
-            .last()
-            .expect(format!("No fragment at index {}", index).as_ref());
+            .nth(index)
+            .unwrap_or_else(|| panic!("No fragment at index {}", index));
     }
 
     fn get_fragment(text: &Document, index: usize) -> &Fragment {
-        return get_fragment_tuple(&text, index).1;
+        return get_fragment_tuple(text, index).1;
     }
 
     #[test]
@@ -436,7 +445,7 @@ mod tests {
     #[test]
     fn test_length() {
         let source = "hello world";
-        let text = Document::from(&source);
+        let text = Document::from(source);
 
         assert_eq!(text.len(), source.len());
     }
@@ -444,7 +453,7 @@ mod tests {
     #[test]
     fn test_display() {
         let source = "source text";
-        let text = Document::from(&source);
+        let text = Document::from(source);
 
         assert_eq!(text.to_string(), source);
     }
@@ -464,7 +473,9 @@ mod tests {
         let mut text = Document::from("hello");
         text.insert(6, " world!");
 
-        let expected = (&6, &Fragment::of_insertion(0, 7));
+        // Offsets past the end are clamped, so this appends at 5 instead of
+        // leaving a one byte gap in the fragment map.
+        let expected = (&5, &Fragment::of_insertion(0, 7));
         assert_eq!(text.fragments.len(), 2);
         assert_eq!(get_fragment_tuple(&text, 1), expected);
     }
@@ -479,264 +490,339 @@ mod tests {
     }
 
     #[test]
-    fn test_find_affected_fragments() {
-        let mut text = Document::from("original");
-        text.insert(8, " with");
-        text.insert(13, " insertions");
+    fn test_delete_fragment_beginning() {
+        let mut text = Document::from("text");
+        text.insert(5, " with fragments");
+        text.delete(&(5..10));
 
-        assert_eq!(text.find_affected_fragments(&0).len(), 3);
-        assert_eq!(text.find_affected_fragments(&1).len(), 3);
-        assert_eq!(text.find_affected_fragments(&7).len(), 3);
-        assert_eq!(text.find_affected_fragments(&8).len(), 2);
-        assert_eq!(text.find_affected_fragments(&13).len(), 1);
+        assert_eq!(text.to_string(), "text fragments");
     }
 
     #[test]
-    fn test_fragment_delete_at_end_operation() {
+    fn test_delete_removes_deleted_fragments() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
+        text.delete(&(13..24));
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(15..24)),
-            vec![FragmentUpdate {
-                operation: FragmentOperation::Trim(0, 9),
-                move_to: 13,
-                key: 13,
-            }]
-        );
+        assert_eq!(text.to_string(), "original with");
     }
 
     #[test]
-    fn test_fragment_delete_at_beginning() {
+    fn test_delete_trims_truncated_fragments() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
+        text.delete(&(15..24));
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(13..22)),
-            vec![FragmentUpdate {
-                operation: FragmentOperation::Trim(9, 0),
-                move_to: 13,
-                key: 13,
-            }]
-        );
+        assert_eq!(text.to_string(), "original with i");
     }
 
     #[test]
-    fn test_fragment_delete_entire_fragment() {
+    fn test_delete_can_split_fragments() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(13..24)),
-            vec![FragmentUpdate {
-                operation: FragmentOperation::Delete(11),
-                move_to: 13,
-                key: 13,
-            }]
-        );
+        text.delete(&(14..20));
+
+        assert_eq!(text.to_string(), "original with ions");
     }
 
     #[test]
-    fn test_delete_multiple_fragments() {
+    fn test_delete_works_across_fragments() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(8..32)),
-            vec![
-                FragmentUpdate {
-                    operation: FragmentOperation::Delete(5),
-                    move_to: 8,
-                    key: 8,
-                },
-                FragmentUpdate {
-                    operation: FragmentOperation::Delete(11),
-                    move_to: 8,
-                    key: 13,
-                }
-            ]
-        );
+        text.delete(&(7..19));
+
+        assert_eq!(text.to_string(), "originations");
     }
 
     #[test]
-    fn test_delete_middle_of_fragment() {
-        let mut text = Document::from("original");
-        text.insert(8, " with");
-        text.insert(13, " insertions");
+    fn test_delete_inside_a_fragment_keys_the_right_half() {
+        let mut text = Document::from("hello world");
+        text.delete(&(2..4));
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(15..20)),
-            vec![FragmentUpdate {
-                operation: FragmentOperation::Split(15, 20),
-                move_to: 13,
-                key: 13,
-            }]
-        );
+        let keys: Vec<usize> = text.fragments.keys().copied().collect();
+        assert_eq!(keys, vec![0, 2]);
+        assert_eq!(text.to_string(), "heo world");
+        assert_eq!(text.len(), 9);
     }
 
     #[test]
-    fn test_delete_fragment_beginning() {
+    fn test_delete_moves_later_fragments_down() {
+        let mut text = Document::from("hello");
+        text.insert(5, " world");
+        text.delete(&(0..2));
+
+        let keys: Vec<usize> = text.fragments.keys().copied().collect();
+        assert_eq!(keys, vec![0, 3]);
+        assert_eq!(text.len(), 9);
+    }
+
+    #[test]
+    fn test_edits_past_the_end_are_clamped() {
         let mut text = Document::from("text");
-        text.insert(5, " with fragments");
-        text.delete(&(5..10));
+        text.insert(10, "!");
+        assert_eq!(text.to_string(), "text!");
 
-        assert_eq!(text.to_string(), "text fragments");
+        text.delete(&(3..10));
+        assert_eq!(text.to_string(), "tex");
+
+        let version = text.version();
+        text.delete(&(2..2));
+        text.delete(&(7..9));
+        assert_eq!(text.version(), version);
+        assert_eq!(text.len(), 3);
     }
 
     #[test]
-    fn test_deletion_adjusts_later_elements() {
-        let mut text = Document::from("original");
-        text.insert(8, " with");
-        text.insert(13, " insertions");
+    fn test_insertion_fragment_creation() {
+        let mut text = Document::new();
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(10..13)),
-            vec![
-                FragmentUpdate {
-                    operation: FragmentOperation::Trim(0, 3),
-                    move_to: 8,
-                    key: 8,
-                },
-                FragmentUpdate {
-                    operation: FragmentOperation::None,
-                    move_to: 10,
-                    key: 13,
-                }
-            ]
-        );
+        assert_eq!(text.insertions.len(), 0);
+        let insertion = text.create_insertion_fragment("content");
+        assert_eq!(text.insertions.len(), insertion.byte_length);
     }
 
     #[test]
-    fn test_deletion_split_adjusts_later_elements() {
-        let mut text = Document::from("original");
-        text.insert(8, " with");
-        text.insert(13, " insertions");
+    fn test_len_after_insertion_and_deletion() {
+        let mut text = Document::from("origin");
+        text.insert(8, " insertion");
+        text.insert(6, "al");
+        text.delete(&(15..18));
 
-        assert_eq!(
-            text.get_changes_for_deletion(&(1..3)),
-            vec![
-                FragmentUpdate {
-                    operation: FragmentOperation::Split(1, 3),
-                    move_to: 0,
-                    key: 0,
-                },
-                FragmentUpdate {
-                    operation: FragmentOperation::None,
-                    move_to: 6,
-                    key: 8,
-                },
-                FragmentUpdate {
-                    operation: FragmentOperation::None,
-                    move_to: 11,
-                    key: 13,
-                },
-            ]
-        );
+        assert_eq!(text.to_string(), "original insert");
+        assert_eq!(text.len(), 15);
     }
 
     #[test]
-    fn test_delete_removes_deleted_fragments() {
+    fn test_delete_returns_removed_text() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
-        text.delete(&(13..24));
 
-        assert_eq!(text.to_string(), "original with");
+        let deleted = text.delete(&(6..15));
+
+        assert_eq!(deleted.start(), 6);
+        assert_eq!(deleted.len(), 9);
+        assert_eq!(deleted.text(&text).to_string(), "al with i");
     }
 
     #[test]
-    fn test_delete_trims_truncated_fragments() {
+    fn test_reinsert_restores_deleted_text() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
-        text.delete(&(15..24));
 
-        assert_eq!(text.to_string(), "original with i");
+        let deleted = text.delete(&(6..15));
+        text.reinsert(deleted.start(), &deleted);
+
+        assert_eq!(text.to_string(), "original with insertions");
+        assert_eq!(text.insertions.len(), 16);
     }
 
     #[test]
-    fn test_delete_can_split_fragments() {
+    fn test_replace_within_fragment() {
+        let mut text = Document::from("hello world");
+        let deleted = text.replace(&(6..11), "there");
+
+        assert_eq!(text.to_string(), "hello there");
+        assert_eq!(deleted.text(&text).to_string(), "world");
+    }
+
+    #[test]
+    fn test_replace_across_fragments() {
         let mut text = Document::from("original");
         text.insert(8, " with");
         text.insert(13, " insertions");
 
-        text.delete(&(14..20));
+        text.replace(&(4..15), "-");
+        assert_eq!(text.to_string(), "orig-nsertions");
 
-        assert_eq!(text.to_string(), "original with ions");
+        text.replace(&(4..5), " and some much longer text, i");
+        assert_eq!(
+            text.to_string(),
+            "orig and some much longer text, insertions"
+        );
     }
 
     #[test]
-    fn test_delete_works_across_fragments() {
-        let mut text = Document::from("original");
-        text.insert(8, " with");
-        text.insert(13, " insertions");
+    fn test_replace_with_nothing() {
+        let mut text = Document::from("text");
+        text.replace(&(0..4), "");
 
-        text.delete(&(7..19));
+        assert_eq!(text.to_string(), "");
+        assert_eq!(text.len(), 0);
+    }
 
-        assert_eq!(text.to_string(), "originations");
+    #[test]
+    fn test_replace_empty_range_inserts() {
+        let mut text = Document::from("text");
+        text.replace(&(2..2), "--");
+
+        assert_eq!(text.to_string(), "te--xt");
     }
 
     #[test]
-    fn test_insertion_fragment_creation() {
-        let mut text = Document::new();
+    fn test_overwrite() {
+        let mut text = Document::from("hello world");
+        let deleted = text.overwrite(6, "there");
+
+        assert_eq!(text.to_string(), "hello there");
+        assert_eq!(deleted.len(), 5);
+    }
+
+    #[test]
+    fn test_overwrite_counts_characters() {
+        let mut text = Document::from("café time");
+        text.overwrite(0, "naïve");
+
+        assert_eq!(text.to_string(), "naïvetime");
+    }
+
+    #[test]
+    fn test_overwrite_extends_the_line() {
+        let mut text = Document::from("ab\ncd");
+        text.overwrite(1, "xyz");
+
+        assert_eq!(text.to_string(), "axyz\ncd");
+    }
+
+    #[test]
+    fn test_duplicate_range() {
+        let mut text = Document::from("one\ntwo\n");
+        let copy = text.duplicate_range(&(0..4), 8);
 
+        assert_eq!(text.to_string(), "one\ntwo\none\n");
+        assert_eq!(copy, 8..12);
         assert_eq!(text.insertions.len(), 0);
-        let insertion = text.create_insertion_fragment("content");
-        assert_eq!(text.insertions.len(), insertion.byte_length);
+        assert_eq!(get_fragment(&text, 1).source, Source::Original);
     }
 
     #[test]
-    fn test_appending_insert_fragment_operations() {
-        let mut text = Document::from("hello");
-        let insert = text.create_insertion_fragment(" world!");
+    fn test_move_range_down() {
+        let mut text = Document::from("one\ntwo\nthree\n");
+        text.insert(0, "zero\n");
+        let moved = text.move_range(&(0..9), 13);
 
-        assert_eq!(
-            text.get_changes_for_insertion(6, insert.clone()),
-            vec![FragmentUpdate {
-                operation: FragmentOperation::Insert(6, insert),
-                move_to: 0,
-                key: 0,
-            }]
-        );
+        assert_eq!(text.to_string(), "two\nzero\none\nthree\n");
+        assert_eq!(moved, 4..13);
+        assert_eq!(text.insertions.len(), 5);
+    }
+
+    #[test]
+    fn test_move_range_up() {
+        let mut text = Document::from("one\ntwo\nthree\n");
+        let moved = text.move_range(&(8..14), 4);
+
+        assert_eq!(text.to_string(), "one\nthree\ntwo\n");
+        assert_eq!(moved, 4..10);
+    }
+
+    #[test]
+    fn test_move_range_is_one_change() {
+        let mut text = Document::from("one\ntwo\nthree\n");
+        let version = text.version();
+        text.move_range(&(0..4), 14);
+
+        assert_eq!(text.to_string(), "two\nthree\none\n");
+        assert_eq!(text.version(), version + 1);
+        assert_eq!(text.changes_since(version).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_move_range_into_itself() {
+        let mut text = Document::from("one two");
+        let moved = text.move_range(&(0..3), 2);
+
+        assert_eq!(text.to_string(), "one two");
+        assert_eq!(moved, 0..3);
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut text = Document::from("first half");
+        text.insert(10, ", second half");
+        let tail = text.split_off(5);
+
+        assert_eq!(text.to_string(), "first");
+        assert_eq!(tail.to_string(), " half, second half");
+        assert_eq!(tail.original.len(), 0);
+        assert_eq!(tail.insertions.len(), 0);
+        assert!(Arc::ptr_eq(
+            tail.get_buffer(&get_fragment(&tail, 0).source),
+            &text.original
+        ));
     }
 
     #[test]
-    fn test_insertions_adjust_later_elements() {
+    fn test_split_halves_edit_independently() {
+        let mut text = Document::from("head");
+        text.insert(4, " tail");
+        let mut tail = text.split_off(4);
+
+        text.insert(4, "!");
+        tail.insert(0, "?");
+
+        assert_eq!(text.to_string(), "head!");
+        assert_eq!(tail.to_string(), "? tail");
+    }
+
+    #[test]
+    fn test_append() {
+        let mut text = Document::from("first");
+        let mut other = Document::from(" second");
+        other.insert(7, " third");
+        text.append(other);
+
+        assert_eq!(text.to_string(), "first second third");
+        assert_eq!(text.buffers.len(), 2);
+    }
+
+    #[test]
+    fn test_split_and_rejoin() {
+        let mut text = Document::from("some text");
+        text.insert(4, " more");
+        let tail = text.split_off(2);
+        text.append(tail);
+
+        assert_eq!(text.to_string(), "some more text");
+        assert_eq!(text.buffers.len(), 0);
+    }
+
+    #[test]
+    fn test_append_empty_document() {
         let mut text = Document::new();
-        text.insert(0, "original ");
-        text.insert(9, "insertions");
-        let insert = text.create_insertion_fragment("with ");
+        text.append(Document::new());
 
-        assert_eq!(
-            text.get_changes_for_insertion(8, insert.clone()),
-            vec![
-                FragmentUpdate {
-                    operation: FragmentOperation::Insert(8, insert.clone()),
-                    move_to: 0,
-                    key: 0,
-                },
-                FragmentUpdate {
-                    operation: FragmentOperation::None,
-                    move_to: 9 + insert.byte_length,
-                    key: 9,
-                }
-            ]
-        )
+        assert_eq!(text.to_string(), "");
+        assert_eq!(text.fragments.len(), 1);
     }
 
     #[test]
-    fn test_len_after_insertion_and_deletion() {
-        let mut text = Document::from("origin");
-        text.insert(8, " insertion");
-        text.insert(6, "al");
-        text.delete(&(15..18));
+    fn test_include() {
+        let mut text = Document::from("before after");
+        text.include(7, String::from("included "));
 
-        assert_eq!(text.to_string(), "original insert");
-        assert_eq!(text.len(), 15);
+        assert_eq!(text.to_string(), "before included after");
+        assert_eq!(text.insertions.len(), 0);
+        assert_eq!(text.buffers.len(), 1);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        let chunks: Vec<&str> = text.chunks(&(4..15)).collect();
+        assert_eq!(chunks, vec!["inal", " with", " i"]);
+
+        assert_eq!(text.chunks(&(3..3)).count(), 0);
+        assert_eq!(text.chunks(&(20..40)).collect::<String>(), "ions");
     }
 
     #[test]
@@ -0,0 +1,278 @@
+use super::indexed_string::Terminator;
+use super::{Deleted, Document};
+use std::ops::Range;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+// Which terminators end a line. A CR directly followed by an LF is a single
+// break when `crlf` is set, and two separate characters otherwise.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LinebreakMode {
+    pub lf: bool,
+    pub cr: bool,
+    pub crlf: bool,
+    // U+2028 LINE SEPARATOR and U+2029 PARAGRAPH SEPARATOR.
+    pub unicode: bool,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        };
+    }
+}
+
+impl Default for LinebreakMode {
+    fn default() -> Self {
+        LinebreakMode {
+            lf: true,
+            cr: true,
+            crlf: true,
+            unicode: false,
+        }
+    }
+}
+
+// A line terminator as it appears in the document, CRLF pairs included.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Break {
+    Ascii(LineEnding),
+    Unicode,
+}
+
+impl Document {
+    pub fn linebreak_mode(&self) -> LinebreakMode {
+        return self.linebreak_mode;
+    }
+
+    pub fn set_linebreak_mode(&mut self, mode: LinebreakMode) {
+        self.linebreak_mode = mode;
+    }
+
+    // Byte ranges of every line terminator under the current mode.
+    pub fn linebreaks(&self) -> Vec<Range<usize>> {
+        let mode = self.linebreak_mode;
+
+        return self
+            .find_breaks()
+            .into_iter()
+            .flat_map(|(range, kind)| match kind {
+                Break::Ascii(LineEnding::CrLf) if !mode.crlf => {
+                    let cr = range.start..range.start + 1;
+                    let lf = range.start + 1..range.end;
+                    vec![
+                        (cr, Break::Ascii(LineEnding::Cr)),
+                        (lf, Break::Ascii(LineEnding::Lf)),
+                    ]
+                }
+                _ => vec![(range, kind)],
+            })
+            .filter(|(_, kind)| match kind {
+                Break::Ascii(LineEnding::Lf) => mode.lf,
+                Break::Ascii(LineEnding::Cr) => mode.cr,
+                Break::Ascii(LineEnding::CrLf) => mode.crlf,
+                Break::Unicode => mode.unicode,
+            })
+            .map(|(range, _)| range)
+            .collect();
+    }
+
+    pub fn line_count(&self) -> usize {
+        return self.linebreaks().len() + 1;
+    }
+
+    // The most common line ending in the text, or LF if there are none.
+    // Ties go to LF, then CRLF.
+    pub fn line_ending(&self) -> LineEnding {
+        let mut counts = [
+            (LineEnding::Lf, 0),
+            (LineEnding::CrLf, 0),
+            (LineEnding::Cr, 0),
+        ];
+
+        for (_, kind) in self.find_breaks() {
+            if let Break::Ascii(ending) = kind {
+                let (_, count) = counts
+                    .iter_mut()
+                    .find(|(known, _)| *known == ending)
+                    .unwrap();
+                *count += 1;
+            }
+        }
+
+        let (ending, _) = counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .expect("Counts are never empty");
+
+        return *ending;
+    }
+
+    // Rewrites every CR, LF and CRLF as a single edit. Text between the line
+    // endings is reused in place and all the new endings share one copy of
+    // the terminator, so the cost doesn't depend on the size of the text.
+    // Unicode separators are left alone.
+    pub fn normalize_line_endings(&mut self, ending: LineEnding) -> Deleted {
+        let changes: Vec<Range<usize>> = self
+            .find_breaks()
+            .into_iter()
+            .filter(|(_, kind)| match kind {
+                Break::Ascii(existing) => *existing != ending,
+                Break::Unicode => false,
+            })
+            .map(|(range, _)| range)
+            .collect();
+
+        let (start, end) = match (changes.first(), changes.last()) {
+            (Some(first), Some(last)) => (first.start, last.end),
+            _ => return Deleted::new(0, vec![]),
+        };
+
+        let terminator = self.create_insertion_fragment(ending.as_str());
+        let mut pieces = vec![];
+        let mut cursor = start;
+
+        for range in changes {
+            pieces.extend(self.fragments.slice_fragments(&(cursor..range.start)));
+            pieces.push(terminator.clone());
+            cursor = range.end;
+        }
+
+        let deleted = self.splice(&(start..end), pieces);
+        self.after_edit();
+
+        return deleted;
+    }
+
+    // Walks the linebreak caches of each fragment's buffer, pairing up CRLFs
+    // even when the two halves sit in different fragments.
+    fn find_breaks(&self) -> Vec<(Range<usize>, Break)> {
+        let mut breaks: Vec<(Range<usize>, Break)> = vec![];
+
+        for (key, frag) in self.fragments.iter() {
+            let source = self.get_fragment_source(frag);
+            let frag_range = frag.byte_offset..frag.byte_offset + frag.byte_length;
+
+            for (offset, terminator) in source.select_terminators(frag_range) {
+                let start = key + offset;
+                let kind = match terminator {
+                    Terminator::LineFeed => Break::Ascii(LineEnding::Lf),
+                    Terminator::CarriageReturn => Break::Ascii(LineEnding::Cr),
+                    _ => Break::Unicode,
+                };
+
+                if let Some((previous, previous_kind)) = breaks.last_mut() {
+                    let follows_cr =
+                        *previous_kind == Break::Ascii(LineEnding::Cr) && previous.end == start;
+
+                    if follows_cr && kind == Break::Ascii(LineEnding::Lf) {
+                        *previous = previous.start..start + 1;
+                        *previous_kind = Break::Ascii(LineEnding::CrLf);
+                        continue;
+                    }
+                }
+
+                let length = match kind {
+                    Break::Unicode => '\u{2028}'.len_utf8(),
+                    _ => 1,
+                };
+
+                breaks.push((start..start + length, kind));
+            }
+        }
+
+        return breaks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linebreaks() {
+        let text = Document::from("a\r\nb\nc\rd");
+
+        assert_eq!(text.linebreaks(), vec![1..3, 4..5, 6..7]);
+        assert_eq!(text.line_count(), 4);
+    }
+
+    #[test]
+    fn test_crlf_across_fragments() {
+        let mut text = Document::from("first\rsecond");
+        text.insert(6, "\n");
+
+        assert_eq!(text.to_string(), "first\r\nsecond");
+        assert_eq!(text.linebreaks(), vec![5..7]);
+    }
+
+    #[test]
+    fn test_linebreak_modes() {
+        let mut text = Document::from("a\r\nb\rc\u{2028}d");
+
+        text.set_linebreak_mode(LinebreakMode {
+            lf: true,
+            cr: false,
+            crlf: false,
+            unicode: true,
+        });
+        assert_eq!(text.linebreaks(), vec![2..3, 6..9]);
+
+        text.set_linebreak_mode(LinebreakMode {
+            lf: false,
+            cr: false,
+            crlf: true,
+            unicode: false,
+        });
+        assert_eq!(text.linebreaks(), vec![1..3]);
+    }
+
+    #[test]
+    fn test_line_ending_detection() {
+        assert_eq!(
+            Document::from("a\r\nb\r\nc\n").line_ending(),
+            LineEnding::CrLf
+        );
+        assert_eq!(Document::from("a\rb\rc\n").line_ending(), LineEnding::Cr);
+        assert_eq!(Document::from("a\nb\r\n").line_ending(), LineEnding::Lf);
+        assert_eq!(Document::from("no breaks").line_ending(), LineEnding::Lf);
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let mut text = Document::from("one\r\ntwo\nthree\rfour\r\n");
+        let deleted = text.normalize_line_endings(LineEnding::Lf);
+
+        assert_eq!(text.to_string(), "one\ntwo\nthree\nfour\n");
+        assert_eq!(deleted.start(), 3);
+        assert_eq!(deleted.text(&text).to_string(), "\r\ntwo\nthree\rfour\r\n");
+        assert_eq!(text.insertions.len(), 1);
+    }
+
+    #[test]
+    fn test_normalize_to_crlf() {
+        let mut text = Document::from("one\ntwo\r\nthree\n");
+        text.normalize_line_endings(LineEnding::CrLf);
+
+        assert_eq!(text.to_string(), "one\r\ntwo\r\nthree\r\n");
+        assert_eq!(text.line_ending(), LineEnding::CrLf);
+    }
+
+    #[test]
+    fn test_normalize_without_changes() {
+        let mut text = Document::from("one\ntwo\n");
+        let deleted = text.normalize_line_endings(LineEnding::Lf);
+
+        assert!(deleted.is_empty());
+        assert_eq!(text.insertions.len(), 0);
+    }
+}
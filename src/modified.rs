@@ -0,0 +1,170 @@
+use super::diff::diff_pieces;
+use super::fragment::{Fragment, Source};
+use super::Document;
+use std::ops::Range;
+
+// The fragment layout when the document was last saved. Comparing layouts
+// rather than text is cheap, and undoing back to this point restores the
+// same pieces, so the document reads as clean again.
+pub struct SavePoint {
+    version: u64,
+    fragments: Vec<Fragment>,
+}
+
+impl SavePoint {
+    pub fn new(version: u64, fragments: Vec<Fragment>) -> Self {
+        SavePoint {
+            version,
+            fragments: normalize(fragments),
+        }
+    }
+}
+
+impl Document {
+    // Makes the current text the one `is_modified` compares against. Saving
+    // and opening do this already.
+    pub fn mark_saved(&mut self) {
+        self.saved = SavePoint::new(self.version(), self.fragments.values().cloned().collect());
+    }
+
+    // Whether the text differs from the save point. Text deleted and typed
+    // again counts as modified, but undoing with `reinsert` doesn't.
+    pub fn is_modified(&self) -> bool {
+        if self.version() == self.saved.version {
+            return false;
+        }
+
+        return normalize(self.fragments.values().cloned().collect()) != self.saved.fragments;
+    }
+
+    // The byte ranges that differ from the save point, for gutter markers.
+    // Empty ranges mark where text was deleted.
+    pub fn modified_ranges(&self) -> Vec<Range<usize>> {
+        if !self.is_modified() {
+            return vec![];
+        }
+
+        let saved = pieces(&self.saved.fragments);
+        let current = pieces(&normalize(self.fragments.values().cloned().collect()));
+
+        return diff_pieces(&saved, &current)
+            .into_iter()
+            .map(|hunk| hunk.new)
+            .collect();
+    }
+}
+
+// Keys fragments by their buffer so pieces can be compared.
+pub fn pieces(fragments: &[Fragment]) -> Vec<(SourceKey, Range<usize>)> {
+    return fragments
+        .iter()
+        .map(|frag| {
+            let key = match frag.source {
+                Source::Original => SourceKey::Original,
+                Source::Insertion => SourceKey::Insertion,
+                Source::Buffer(id) => SourceKey::Buffer(id.index()),
+            };
+
+            (key, frag.byte_offset..frag.byte_offset + frag.byte_length)
+        })
+        .collect();
+}
+
+// `Source`, but ordered.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum SourceKey {
+    Original,
+    Insertion,
+    Buffer(usize),
+}
+
+// Joins neighbouring fragments that continue each other in the same buffer
+// and drops empty ones, so edits that split a fragment and put it back
+// compare equal to the untouched fragment.
+pub fn normalize(fragments: Vec<Fragment>) -> Vec<Fragment> {
+    let mut normalized: Vec<Fragment> = vec![];
+
+    for frag in fragments.into_iter().filter(|frag| frag.byte_length > 0) {
+        if let Some(last) = normalized.last_mut() {
+            if last.source == frag.source && last.byte_offset + last.byte_length == frag.byte_offset
+            {
+                last.byte_length += frag.byte_length;
+                continue;
+            }
+        }
+
+        normalized.push(frag);
+    }
+
+    return normalized;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undo_back_to_the_save_point() {
+        let mut text = Document::from("hello world");
+        assert!(!text.is_modified());
+
+        let deleted = text.delete(&(5..11));
+        assert!(text.is_modified());
+
+        text.reinsert(5, &deleted);
+        assert!(!text.is_modified());
+
+        text.insert(5, "!");
+        text.delete(&(5..6));
+        assert!(!text.is_modified());
+    }
+
+    #[test]
+    fn test_mark_saved() {
+        let mut text = Document::from("hello");
+        text.insert(5, " world");
+        text.mark_saved();
+        assert!(!text.is_modified());
+
+        let deleted = text.delete(&(0..6));
+        assert!(text.is_modified());
+        text.reinsert(0, &deleted);
+        assert!(!text.is_modified());
+
+        // Retyped text comes from new bytes, so it's a change.
+        text.replace(&(0..5), "hello");
+        assert!(text.is_modified());
+    }
+
+    #[test]
+    fn test_modified_ranges() {
+        let mut text = Document::from("one two three four");
+        assert!(text.modified_ranges().is_empty());
+
+        text.replace(&(4..7), "2");
+        text.delete(&(12..14));
+        text.insert(text.len(), "!");
+
+        assert_eq!(text.to_string(), "one 2 three ur!");
+        assert_eq!(text.modified_ranges(), vec![4..5, 12..12, 14..15]);
+    }
+
+    #[test]
+    fn test_split_off_is_an_edit() {
+        let mut text = Document::from("hello world");
+        let tail = text.split_off(5);
+
+        assert_eq!(tail.to_string(), " world");
+        assert_eq!(
+            tail.changes_since(0),
+            Some(vec![crate::Change {
+                start: 0,
+                deleted: 0,
+                inserted: 6
+            }])
+        );
+        assert!(tail.is_modified());
+        assert_eq!(tail.modified_ranges(), vec![0..6]);
+        assert!(text.is_modified());
+    }
+}
@@ -0,0 +1,370 @@
+use super::diff::{diff_pieces, Hunk};
+use super::fragment::{Fragment, Source};
+use super::modified::{normalize, pieces, SourceKey};
+use super::{Deleted, Document};
+use std::fmt::Write;
+use std::ops::Range;
+
+// Changed lines in a unified diff: half-open line ranges on each side.
+struct LineHunk {
+    old: Range<usize>,
+    new: Range<usize>,
+}
+
+impl Document {
+    // What differs from the text the document was created with, as hunks
+    // from byte ranges of the original to byte ranges of the current text.
+    // Worked out from where the fragments' bytes come from, so no text is
+    // compared.
+    pub fn changes_since_open(&self) -> Vec<Hunk> {
+        let original = vec![(SourceKey::Original, 0..self.original.len())];
+        let current = pieces(&normalize(self.fragments.values().cloned().collect()));
+
+        return diff_pieces(&original, &current);
+    }
+
+    // Puts back the original text of every change since open that overlaps
+    // `range`, as one edit. Changes are reverted whole, since part of one
+    // has no clear counterpart in the original.
+    pub fn revert_to_original(&mut self, range: &Range<usize>) -> Deleted {
+        let touches = |new: &Range<usize>| match new.is_empty() || range.is_empty() {
+            true => new.start <= range.end && range.start <= new.end,
+            false => new.start < range.end && range.start < new.end,
+        };
+
+        let replacements = self
+            .changes_since_open()
+            .into_iter()
+            .filter(|hunk| touches(&hunk.new))
+            .map(|hunk| {
+                let pieces = match hunk.old.is_empty() {
+                    true => vec![],
+                    false => vec![Fragment::new(
+                        Source::Original,
+                        hunk.old.start,
+                        hunk.old.len(),
+                    )],
+                };
+
+                (hunk.new, pieces)
+            })
+            .collect();
+
+        return self.splice_replacements(replacements);
+    }
+
+    // The changes since open as a unified diff with `context` lines around
+    // each hunk, e.g. for a "show unsaved changes" view. Lines end at LF.
+    pub fn unified_diff(&self, old_name: &str, new_name: &str, context: usize) -> String {
+        let hunks = self.changes_since_open();
+
+        if hunks.is_empty() {
+            return String::new();
+        }
+
+        let old = self.original.slice(0..self.original.len());
+        let new = self.to_string();
+        let old_lines = line_starts(old);
+        let new_lines = line_starts(&new);
+
+        let mut output = format!("--- {}\n+++ {}\n", old_name, new_name);
+        let line_hunks = to_line_hunks(&hunks, old, &new);
+        let mut group_start = 0;
+
+        while group_start < line_hunks.len() {
+            // Hunks close enough to share context go under one header.
+            let mut group_end = group_start + 1;
+            while group_end < line_hunks.len()
+                && line_hunks[group_end].old.start - line_hunks[group_end - 1].old.end
+                    <= 2 * context
+            {
+                group_end += 1;
+            }
+
+            let first = &line_hunks[group_start];
+            let last = &line_hunks[group_end - 1];
+            let before = first.old.start.min(context);
+            let old_end = (last.old.end + context).min(old_lines.len());
+            let after = old_end - last.old.end;
+            let old_range = first.old.start - before..old_end;
+            let new_range = first.new.start - before..last.new.end + after;
+
+            let _ = writeln!(
+                output,
+                "@@ -{} +{} @@",
+                header_range(&old_range),
+                header_range(&new_range)
+            );
+
+            let mut old_line = old_range.start;
+            for hunk in &line_hunks[group_start..group_end] {
+                push_lines(&mut output, ' ', old, &old_lines, old_line..hunk.old.start);
+                push_lines(&mut output, '-', old, &old_lines, hunk.old.clone());
+                push_lines(&mut output, '+', &new, &new_lines, hunk.new.clone());
+                old_line = hunk.old.end;
+            }
+            push_lines(&mut output, ' ', old, &old_lines, old_line..old_range.end);
+
+            group_start = group_end;
+        }
+
+        return output;
+    }
+}
+
+// Where each line starts. A trailing LF doesn't start another line.
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![];
+    let mut start = 0;
+
+    while start < text.len() {
+        starts.push(start);
+        start = text[start..]
+            .find('\n')
+            .map_or(text.len(), |linebreak| start + linebreak + 1);
+    }
+
+    return starts;
+}
+
+// Widens byte hunks to whole lines, joining those that end up sharing one.
+// The text between two hunks is the same on both sides, so a line is
+// widened by the same number of bytes on each.
+fn to_line_hunks(hunks: &[Hunk], old: &str, new: &str) -> Vec<LineHunk> {
+    let is_line_start = |text: &str, at: usize| at == 0 || text.as_bytes()[at - 1] == b'\n';
+    let mut line_hunks: Vec<LineHunk> = vec![];
+    let mut index = 0;
+    // Line numbers are counted on from the previous hunk rather than from
+    // the start of the text each time. Each side: (offset, line there).
+    let mut old_cursor = (0, 0);
+    let mut new_cursor = (0, 0);
+
+    while index < hunks.len() {
+        let mut old_range = hunks[index].old.clone();
+        let mut new_range = hunks[index].new.clone();
+
+        // The previous hunk ended at a line start, so this line can't begin
+        // before it.
+        let line_start = old[old_cursor.0..old_range.start]
+            .rfind('\n')
+            .map_or(old_cursor.0, |at| old_cursor.0 + at + 1);
+        let prefix = old_range.start - line_start;
+        old_range.start -= prefix;
+        new_range.start -= prefix;
+
+        loop {
+            if is_line_start(old, old_range.end) && is_line_start(new, new_range.end) {
+                break;
+            }
+
+            let suffix = old[old_range.end..]
+                .find('\n')
+                .map_or(old.len() - old_range.end, |at| at + 1);
+
+            let line_end = old_range.end + suffix;
+
+            match hunks.get(index + 1) {
+                // The next hunk starts on this line, so take it in too.
+                Some(next)
+                    if next.old.start < line_end
+                        || (next.old.start == line_end && !is_line_start(old, line_end)) =>
+                {
+                    index += 1;
+                    old_range.end = next.old.end;
+                    new_range.end = next.new.end;
+                }
+                _ => {
+                    old_range.end += suffix;
+                    new_range.end += suffix;
+                    break;
+                }
+            }
+        }
+
+        let line_count = |text: &str, range: &Range<usize>| {
+            let breaks = text[range.clone()].matches('\n').count();
+
+            match range.is_empty() || text[..range.end].ends_with('\n') {
+                true => breaks,
+                false => breaks + 1,
+            }
+        };
+
+        let advance = |text: &str, cursor: (usize, usize), to: usize| {
+            (to, cursor.1 + text[cursor.0..to].matches('\n').count())
+        };
+
+        let old_start = advance(old, old_cursor, old_range.start).1;
+        let new_start = advance(new, new_cursor, new_range.start).1;
+        line_hunks.push(LineHunk {
+            old: old_start..old_start + line_count(old, &old_range),
+            new: new_start..new_start + line_count(new, &new_range),
+        });
+
+        old_cursor = advance(old, (old_range.start, old_start), old_range.end);
+        new_cursor = advance(new, (new_range.start, new_start), new_range.end);
+
+        index += 1;
+    }
+
+    return line_hunks;
+}
+
+// "start,count" with one-based lines. An empty range names the line before
+// it, and a count of one is left out, as GNU diff does.
+fn header_range(lines: &Range<usize>) -> String {
+    return match lines.len() {
+        0 => format!("{},0", lines.start),
+        1 => format!("{}", lines.start + 1),
+        count => format!("{},{}", lines.start + 1, count),
+    };
+}
+
+fn push_lines(
+    output: &mut String,
+    prefix: char,
+    text: &str,
+    starts: &[usize],
+    lines: Range<usize>,
+) {
+    for line in lines {
+        let end = starts.get(line + 1).copied().unwrap_or(text.len());
+        output.push(prefix);
+        output.push_str(&text[starts[line]..end]);
+
+        if !text[..end].ends_with('\n') {
+            output.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes_since_open() {
+        let mut text = Document::from("one two three four");
+        text.replace(&(4..7), "2");
+        text.delete(&(12..14));
+        text.move_range(&(0..4), text.len());
+        // Unlike `modified_ranges`, saving doesn't reset this.
+        text.mark_saved();
+
+        assert_eq!(text.to_string(), "2 three urone ");
+        assert_eq!(
+            text.changes_since_open(),
+            vec![
+                Hunk {
+                    old: 0..7,
+                    new: 0..1
+                },
+                Hunk {
+                    old: 14..16,
+                    new: 8..8
+                },
+                Hunk {
+                    old: 18..18,
+                    new: 10..14
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_revert_to_original() {
+        let mut text = Document::from("one two three four five");
+        text.replace(&(0..3), "1");
+        text.insert(5, "!");
+        text.delete(&(12..17));
+        text.insert(text.len(), " six");
+
+        assert_eq!(text.to_string(), "1 two! three five six");
+        let deleted = text.revert_to_original(&(2..13));
+
+        // Only the changes inside the range are undone.
+        assert_eq!(text.to_string(), "1 two three four five six");
+        assert_eq!(deleted.start(), 5);
+        assert_eq!(deleted.text(&text).to_string(), "! three");
+
+        text.revert_to_original(&(0..0));
+        text.revert_to_original(&(text.len()..text.len()));
+        assert_eq!(text.to_string(), "one two three four five");
+        assert!(text.changes_since_open().is_empty());
+    }
+
+    #[test]
+    fn test_revert_after_many_edits() {
+        let mut text = Document::from("alpha\nbeta\ngamma\n");
+
+        for index in 0..50 {
+            text.insert(6, &index.to_string());
+            text.delete(&(6..7));
+        }
+        text.insert(0, "> ");
+
+        let line = text.to_string().find("gamma").unwrap();
+        text.revert_to_original(&(7..line));
+        assert_eq!(text.to_string(), "> alpha\nbeta\ngamma\n");
+
+        let version = text.version();
+        text.revert_to_original(&(0..1));
+        assert_eq!(text.to_string(), "alpha\nbeta\ngamma\n");
+        assert_eq!(text.version(), version + 1);
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let mut text = Document::from("a\nb\nc\nd\ne\nf\ng\nh\ni\n");
+        text.replace(&(2..3), "B");
+        text.insert(16, "new\n");
+
+        assert_eq!(
+            text.unified_diff("a.txt", "b.txt", 1),
+            "--- a.txt\n+++ b.txt\n\
+             @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+             @@ -8,2 +8,3 @@\n h\n+new\n i\n"
+        );
+        assert_eq!(Document::from("same").unified_diff("a", "b", 3), "");
+    }
+
+    #[test]
+    fn test_unified_diff_without_final_newline() {
+        let mut text = Document::from("first\nlast");
+        text.insert(10, "!");
+        text.insert(0, "zeroth\n");
+
+        assert_eq!(
+            text.unified_diff("a", "b", 3),
+            "--- a\n+++ b\n\
+             @@ -1,2 +1,3 @@\n+zeroth\n first\n-last\n\\ No newline at end of file\n\
+             +last!\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_changes_within_a_line_are_joined() {
+        let mut text = Document::from("one two three\nfour\n");
+        text.replace(&(0..3), "1");
+        text.replace(&(6..11), "3");
+
+        assert_eq!(text.changes_since_open().len(), 2);
+        assert_eq!(
+            text.unified_diff("a", "b", 0),
+            "--- a\n+++ b\n@@ -1 +1 @@\n-one two three\n+1 two 3\n"
+        );
+    }
+
+    #[test]
+    fn test_changes_on_a_last_line_without_newline() {
+        let mut text = Document::from("x\nabc");
+        text.delete(&(3..4));
+        text.insert(text.len(), "!");
+
+        assert_eq!(
+            text.unified_diff("a", "b", 0),
+            "--- a\n+++ b\n@@ -2 +2 @@\n-abc\n\\ No newline at end of file\n\
+             +ac!\n\\ No newline at end of file\n"
+        );
+    }
+}
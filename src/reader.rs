@@ -0,0 +1,171 @@
+use super::Document;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+// A cursor over a document for consumers that expect `Read`, `BufRead` or
+// `Seek`. Reads come straight out of the fragment buffers.
+pub struct DocumentReader<'a> {
+    document: &'a Document,
+    position: usize,
+}
+
+impl Document {
+    pub fn reader(&self) -> DocumentReader<'_> {
+        return DocumentReader {
+            document: self,
+            position: 0,
+        };
+    }
+}
+
+impl<'a> Read for DocumentReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+
+        return Ok(count);
+    }
+}
+
+impl<'a> BufRead for DocumentReader<'a> {
+    // The rest of the fragment under the cursor. The cursor may sit inside
+    // a character after a seek, so this goes by bytes.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let document = self.document;
+        let chunk = document
+            .byte_chunks(&(self.position..document.len()))
+            .next()
+            .unwrap_or(&[]);
+
+        return Ok(chunk);
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt;
+    }
+}
+
+impl<'a> Seek for DocumentReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.document.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.position as u64).checked_add_signed(offset),
+        };
+
+        let position = match position.and_then(|position| usize::try_from(position).ok()) {
+            Some(position) => position,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid seek to a negative or overflowing position",
+                ))
+            }
+        };
+
+        // Like files, seeking past the end is fine. Reads just come up empty.
+        self.position = position;
+
+        return Ok(self.position as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_document() -> Document {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        return text;
+    }
+
+    #[test]
+    fn test_read_to_string() {
+        let text = create_document();
+        let mut output = String::new();
+        text.reader().read_to_string(&mut output).unwrap();
+
+        assert_eq!(output, "original with insertions");
+    }
+
+    #[test]
+    fn test_fill_buf_returns_fragments() {
+        let text = create_document();
+        let mut reader = text.reader();
+        reader.seek(SeekFrom::Start(4)).unwrap();
+
+        assert_eq!(reader.fill_buf().unwrap(), b"inal");
+        reader.consume(4);
+        assert_eq!(reader.fill_buf().unwrap(), b" with");
+    }
+
+    #[test]
+    fn test_read_lines() {
+        let text = Document::from("first\nsecond\nthird");
+        let lines: Vec<String> = text.reader().lines().map(Result::unwrap).collect();
+
+        assert_eq!(lines, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn test_seek() {
+        let text = create_document();
+        let mut reader = text.reader();
+
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 14);
+        assert_eq!(reader.seek(SeekFrom::Current(-1)).unwrap(), 13);
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        assert_eq!(output, " insertions");
+    }
+
+    #[test]
+    fn test_seek_out_of_bounds() {
+        let text = create_document();
+        let mut reader = text.reader();
+
+        assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+        assert_eq!(reader.seek(SeekFrom::Start(100)).unwrap(), 100);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seek_overflow() {
+        let text = create_document();
+        let mut reader = text.reader();
+        reader.read_exact(&mut [0; 3]).unwrap();
+
+        let far = 3 + i64::MAX as u64;
+        assert_eq!(reader.seek(SeekFrom::Current(i64::MAX)).unwrap(), far);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+
+        let error = reader.seek(SeekFrom::Current(i64::MAX)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(reader.stream_position().unwrap(), far);
+
+        assert_eq!(reader.seek(SeekFrom::Start(u64::MAX)).unwrap(), u64::MAX);
+        assert!(reader.seek(SeekFrom::Current(1)).is_err());
+        assert!(reader.seek(SeekFrom::End(-1000)).is_err());
+    }
+
+    #[test]
+    fn test_seek_inside_a_character() {
+        let mut text = Document::from("é");
+        text.insert(2, "ü!");
+        let mut reader = text.reader();
+        reader.seek(SeekFrom::Start(1)).unwrap();
+
+        let mut output = vec![];
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, "éü!".as_bytes()[1..]);
+
+        reader.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(reader.fill_buf().unwrap(), &"ü!".as_bytes()[1..]);
+    }
+}
@@ -0,0 +1,675 @@
+use super::Document;
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::hybrid::LazyStateID;
+use regex_automata::nfa::thompson;
+use regex_automata::util::captures::GroupInfo;
+use regex_automata::util::start;
+use regex_automata::{meta, Anchored, Input, MatchKind, PatternID};
+use regex_syntax::hir::{Class, Hir, HirKind};
+use std::{error::Error, fmt, ops::Range};
+
+// Bytes of context copied on either side of a match, enough to decode the
+// characters next to it for `\b` and friends.
+const CONTEXT: usize = 4;
+
+// A regular expression compiled for searching documents. Matches are found
+// by lazy DFAs fed one byte at a time from the fragments, so no text is
+// copied. Capture groups are resolved afterwards on a copy of the match.
+pub struct Regex {
+    // Finds where the leftmost match ends.
+    forward: DFA,
+    // Runs backwards: anchored to find where a match starts, unanchored to
+    // find the last match before a point.
+    reverse: DFA,
+    captures: meta::Regex,
+    // Whether a match can run over a linebreak.
+    spans_lines: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RegexError {
+    message: String,
+}
+
+// A match and its capture groups, in absolute byte offsets.
+#[derive(Debug, Clone)]
+pub struct Captures {
+    groups: Vec<Option<Range<usize>>>,
+    group_info: GroupInfo,
+}
+
+// Successive non-overlapping matches in a range, either front to back or
+// back to front. See `Document::regex_find_iter`.
+pub struct RegexMatches<'a> {
+    document: &'a Document,
+    regex: &'a Regex,
+    forward_cache: Cache,
+    reverse_cache: Cache,
+    // The part of the range still to be searched.
+    remaining: Range<usize>,
+    reverse: bool,
+    // The edge of the previous match facing the unsearched text. An empty
+    // match touching it is skipped, like the `regex` crate does.
+    last: Option<usize>,
+    // Set once the DFAs give up, after which the rest of the range is
+    // searched in this copy.
+    fallback: Option<Fallback>,
+}
+
+// A copy of the range being searched, made the first time the DFAs give
+// up, so it's copied once rather than for every match.
+struct Fallback {
+    // Where the copy starts in the document.
+    offset: usize,
+    haystack: Vec<u8>,
+    // For searching back to front: every match in the copy, front to back.
+    // Those past the remaining range are dropped as the search moves back.
+    matches: Vec<Range<usize>>,
+}
+
+// A DFA stopped on a byte it can't handle, e.g. non-ASCII text next to a
+// Unicode word boundary.
+struct Quit;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, RegexError> {
+        let forward = DFA::builder()
+            .configure(DFA::config().unicode_word_boundary(true))
+            .build(pattern)?;
+        let reverse = DFA::builder()
+            .configure(
+                DFA::config()
+                    .match_kind(MatchKind::All)
+                    .unicode_word_boundary(true),
+            )
+            .thompson(thompson::Config::new().reverse(true))
+            .build(pattern)?;
+
+        let spans_lines = match regex_syntax::parse(pattern) {
+            Ok(hir) => matches_linebreak(&hir),
+            // The DFAs accepted it, so this shouldn't happen, but assuming
+            // the worst is always safe.
+            Err(_) => true,
+        };
+
+        return Ok(Regex {
+            forward,
+            reverse,
+            captures: meta::Regex::new(pattern)?,
+            spans_lines,
+        });
+    }
+
+    pub(crate) fn spans_lines(&self) -> bool {
+        return self.spans_lines;
+    }
+
+    fn start(
+        dfa: &DFA,
+        cache: &mut Cache,
+        anchored: Anchored,
+        look_behind: Option<u8>,
+    ) -> Result<LazyStateID, Quit> {
+        let config = start::Config::new()
+            .anchored(anchored)
+            .look_behind(look_behind);
+
+        return dfa.start_state(cache, &config).map_err(|_| Quit);
+    }
+
+    // Feeds `bytes` through a DFA until it dies, and returns the last
+    // position where it was in a match state. Each byte comes with the
+    // position a match would be reported at if it completes one. `edge` is
+    // the position to report if the bytes run off the end of the document.
+    fn run(
+        dfa: &DFA,
+        cache: &mut Cache,
+        mut state: LazyStateID,
+        bytes: impl Iterator<Item = (usize, u8)>,
+        edge: Option<usize>,
+        earliest: bool,
+    ) -> Result<Option<usize>, Quit> {
+        let mut found = None;
+
+        for (position, byte) in bytes {
+            state = dfa.next_state(cache, state, byte).map_err(|_| Quit)?;
+
+            if state.is_match() {
+                found = Some(position);
+
+                if earliest {
+                    return Ok(found);
+                }
+            } else if state.is_dead() {
+                return Ok(found);
+            } else if state.is_quit() {
+                return Err(Quit);
+            }
+        }
+
+        if let Some(position) = edge {
+            state = dfa.next_eoi_state(cache, state).map_err(|_| Quit)?;
+
+            if state.is_match() {
+                found = Some(position);
+            }
+        }
+
+        return Ok(found);
+    }
+}
+
+// Whether any part of the pattern can match a `\n`. Errs on the side of
+// yes, e.g. for repetitions that can't repeat.
+fn matches_linebreak(hir: &Hir) -> bool {
+    return match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => false,
+        HirKind::Literal(literal) => literal.0.contains(&b'\n'),
+        HirKind::Class(Class::Unicode(class)) => class
+            .ranges()
+            .iter()
+            .any(|range| range.start() <= '\n' && '\n' <= range.end()),
+        HirKind::Class(Class::Bytes(class)) => class
+            .ranges()
+            .iter()
+            .any(|range| range.start() <= b'\n' && b'\n' <= range.end()),
+        HirKind::Repetition(repetition) => matches_linebreak(&repetition.sub),
+        HirKind::Capture(capture) => matches_linebreak(&capture.sub),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => hirs.iter().any(matches_linebreak),
+    };
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return write!(fmt, "Invalid regex: {}", self.message);
+    }
+}
+
+impl Error for RegexError {}
+
+impl From<regex_automata::hybrid::BuildError> for RegexError {
+    fn from(error: regex_automata::hybrid::BuildError) -> Self {
+        return RegexError {
+            message: error.to_string(),
+        };
+    }
+}
+
+impl From<meta::BuildError> for RegexError {
+    fn from(error: meta::BuildError) -> Self {
+        return RegexError {
+            message: error.to_string(),
+        };
+    }
+}
+
+impl Captures {
+    // The whole match.
+    pub fn range(&self) -> Range<usize> {
+        return self.groups[0].clone().expect("Group 0 always matches");
+    }
+
+    // A capture group by index, or `None` if it didn't take part.
+    pub fn get(&self, index: usize) -> Option<Range<usize>> {
+        return self.groups.get(index).cloned().flatten();
+    }
+
+    pub fn name(&self, name: &str) -> Option<Range<usize>> {
+        let index = self.group_info.to_index(PatternID::ZERO, name)?;
+
+        return self.get(index);
+    }
+
+    // Number of groups in the pattern, including the whole match.
+    pub fn group_count(&self) -> usize {
+        return self.groups.len();
+    }
+}
+
+impl<'a> Iterator for RegexMatches<'a> {
+    type Item = Captures;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let found = match (self.fallback.is_some(), self.reverse) {
+                (true, _) => Ok(self.find_in_copy()),
+                (false, true) => self.find_backward(),
+                (false, false) => self.find_forward(),
+            };
+            let found = found.unwrap_or_else(|_| self.find_in_copy())?;
+            let (near, far) = match self.reverse {
+                true => (found.end, found.start),
+                false => (found.start, found.end),
+            };
+
+            // Skip empty matches touching the last match or splitting a
+            // character, moving on by one byte.
+            if found.is_empty()
+                && (self.last == Some(near) || !self.document.is_char_boundary(near))
+            {
+                if self.reverse && near > self.remaining.start {
+                    self.remaining.end = near - 1;
+                } else if !self.reverse && near < self.remaining.end {
+                    self.remaining.start = near + 1;
+                } else {
+                    return None;
+                }
+
+                continue;
+            }
+
+            self.last = Some(far);
+
+            match self.reverse {
+                true => self.remaining.end = far,
+                false => self.remaining.start = far,
+            }
+
+            return Some(self.captures(found));
+        }
+    }
+}
+
+impl<'a> RegexMatches<'a> {
+    fn find_forward(&mut self) -> Result<Option<Range<usize>>, Quit> {
+        let Range { start, end } = self.remaining;
+        let match_end = match self.find_end(start, end, Anchored::No)? {
+            Some(match_end) => match_end,
+            None => return Ok(None),
+        };
+        let match_start = self
+            .find_start(start, match_end, Anchored::Yes)?
+            .expect("Match has no start");
+
+        return Ok(Some(match_start..match_end));
+    }
+
+    // Finds the last match start, then the end of the match from there,
+    // then stretches the start back as far as that match reaches, so a
+    // match isn't cut short just because the search runs backwards.
+    fn find_backward(&mut self) -> Result<Option<Range<usize>>, Quit> {
+        let Range { start, end } = self.remaining;
+        let last_start = match self.find_start(start, end, Anchored::No)? {
+            Some(last_start) => last_start,
+            None => return Ok(None),
+        };
+        let match_end = self
+            .find_end(last_start, end, Anchored::Yes)?
+            .expect("Match has no end");
+        let match_start = self
+            .find_start(start, match_end, Anchored::Yes)?
+            .expect("Match has no start");
+
+        return Ok(Some(match_start..match_end));
+    }
+
+    // Where the leftmost-first match in `start..end` ends. The bytes
+    // around the range are fed in too, so anchors and word boundaries see
+    // the text beyond it.
+    fn find_end(
+        &mut self,
+        start: usize,
+        end: usize,
+        anchored: Anchored,
+    ) -> Result<Option<usize>, Quit> {
+        let document = self.document;
+        let dfa = &self.regex.forward;
+        let look_behind = start.checked_sub(1).and_then(|at| document.byte_at(at));
+        let state = Regex::start(dfa, &mut self.forward_cache, anchored, look_behind)?;
+        let bytes = document.bytes_from(start).take(end + 1 - start);
+        let edge = Some(end).filter(|end| *end == document.len());
+
+        return Regex::run(dfa, &mut self.forward_cache, state, bytes, edge, false);
+    }
+
+    // Where the match ending at `end` starts when anchored, or else the
+    // start of the last match in `start..end`.
+    fn find_start(
+        &mut self,
+        start: usize,
+        end: usize,
+        anchored: Anchored,
+    ) -> Result<Option<usize>, Quit> {
+        let document = self.document;
+        let dfa = &self.regex.reverse;
+        let state = Regex::start(
+            dfa,
+            &mut self.reverse_cache,
+            anchored,
+            document.byte_at(end),
+        )?;
+        let bytes = document
+            .bytes_before(end)
+            .take(end + 1 - start)
+            .map(|(at, byte)| (at + 1, byte));
+        let edge = Some(start).filter(|start| *start == 0);
+        let earliest = anchored == Anchored::No;
+
+        return Regex::run(dfa, &mut self.reverse_cache, state, bytes, edge, earliest);
+    }
+
+    // The slow path for when the DFAs give up: copies what's left of the
+    // range once and searches it as a string from then on.
+    fn find_in_copy(&mut self) -> Option<Range<usize>> {
+        let regex = &self.regex.captures;
+        let Range { start, end } = self.remaining;
+
+        let reverse = self.reverse;
+        let document = self.document;
+        let fallback = self.fallback.get_or_insert_with(|| {
+            let window = start.saturating_sub(CONTEXT)..(end + CONTEXT).min(document.len());
+            let haystack = document.copy_bytes(&window);
+            let matches = match reverse {
+                true => regex
+                    .find_iter(Input::new(&haystack).span(start - window.start..end - window.start))
+                    .map(|found| found.start() + window.start..found.end() + window.start)
+                    .collect(),
+                false => vec![],
+            };
+
+            Fallback {
+                offset: window.start,
+                haystack,
+                matches,
+            }
+        });
+
+        if reverse {
+            while fallback.matches.last().is_some_and(|found| found.end > end) {
+                fallback.matches.pop();
+            }
+
+            return fallback
+                .matches
+                .last()
+                .cloned()
+                .filter(|found| found.start >= start);
+        }
+
+        let offset = fallback.offset;
+        let input = Input::new(&fallback.haystack).span(start - offset..end - offset);
+
+        return regex
+            .search(&input)
+            .map(|found| found.start() + offset..found.end() + offset);
+    }
+
+    // Resolves capture groups by rerunning the match on a copy of its text.
+    fn captures(&self, found: Range<usize>) -> Captures {
+        let regex = &self.regex.captures;
+        let mut groups = vec![Some(found.clone())];
+
+        if regex.captures_len() > 1 {
+            let window =
+                found.start.saturating_sub(CONTEXT)..(found.end + CONTEXT).min(self.document.len());
+            let haystack = self.document.copy_bytes(&window);
+            let input = Input::new(&haystack)
+                .span(found.start - window.start..found.end - window.start)
+                .anchored(Anchored::Yes);
+            let mut slots = regex.create_captures();
+            regex.search_captures(&input, &mut slots);
+
+            groups = (0..slots.group_len())
+                .map(|index| {
+                    slots
+                        .get_group(index)
+                        .map(|span| span.start + window.start..span.end + window.start)
+                })
+                .collect();
+        }
+
+        return Captures {
+            groups,
+            group_info: regex.group_info().clone(),
+        };
+    }
+}
+
+impl Document {
+    // Every match of `regex` in a byte range, front to back.
+    pub fn regex_find_iter<'a>(
+        &'a self,
+        regex: &'a Regex,
+        range: &Range<usize>,
+    ) -> RegexMatches<'a> {
+        return self.regex_matches(regex, range, false);
+    }
+
+    // Every match of `regex` in a byte range, back to front, for "find
+    // previous".
+    pub fn regex_rfind_iter<'a>(
+        &'a self,
+        regex: &'a Regex,
+        range: &Range<usize>,
+    ) -> RegexMatches<'a> {
+        return self.regex_matches(regex, range, true);
+    }
+
+    fn regex_matches<'a>(
+        &'a self,
+        regex: &'a Regex,
+        range: &Range<usize>,
+        reverse: bool,
+    ) -> RegexMatches<'a> {
+        let end = range.end.min(self.len());
+
+        return RegexMatches {
+            document: self,
+            regex,
+            forward_cache: regex.forward.create_cache(),
+            reverse_cache: regex.reverse.create_cache(),
+            remaining: range.start.min(end)..end,
+            reverse,
+            last: None,
+            fallback: None,
+        };
+    }
+
+    // Bytes from `at` onwards, with their offsets.
+    pub(crate) fn bytes_from(&self, at: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+        let bytes = self
+            .byte_chunks(&(at..self.len()))
+            .flat_map(|chunk| chunk.iter().copied());
+
+        return (at..).zip(bytes);
+    }
+
+    // Bytes before `at`, nearest first, with their offsets.
+    pub(crate) fn bytes_before(&self, at: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+        let bytes = self
+            .byte_chunks(&(0..at))
+            .rev()
+            .flat_map(|chunk| chunk.iter().rev().copied());
+
+        return (0..at).rev().zip(bytes);
+    }
+
+    fn byte_at(&self, at: usize) -> Option<u8> {
+        return self.bytes_from(at).next().map(|(_, byte)| byte);
+    }
+
+    fn copy_bytes(&self, range: &Range<usize>) -> Vec<u8> {
+        return self.byte_chunks(range).flatten().copied().collect();
+    }
+
+    pub(crate) fn is_char_boundary(&self, at: usize) -> bool {
+        // UTF-8 continuation bytes look like 0b10xxxxxx.
+        return self.byte_at(at).is_none_or(|byte| byte & 0xC0 != 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragmented(text: &str) -> Document {
+        let mut document = Document::new();
+
+        for character in text.chars().rev() {
+            document.insert(0, &character.to_string());
+        }
+
+        return document;
+    }
+
+    fn ranges(matches: RegexMatches) -> Vec<Range<usize>> {
+        return matches.map(|found| found.range()).collect();
+    }
+
+    // What the `regex` crate family finds on the same text as a string.
+    fn expected(pattern: &str, text: &str, range: Range<usize>) -> Vec<Range<usize>> {
+        let regex = meta::Regex::new(pattern).unwrap();
+        let input = Input::new(text).span(range);
+
+        return regex.find_iter(input).map(|found| found.range()).collect();
+    }
+
+    #[test]
+    fn test_find_across_fragments() {
+        let regex = Regex::new(r"\d+").unwrap();
+        let text = fragmented("a1 b22 c333");
+
+        assert_eq!(
+            ranges(text.regex_find_iter(&regex, &(0..11))),
+            vec![1..2, 4..6, 8..11]
+        );
+        assert_eq!(
+            ranges(text.regex_find_iter(&regex, &(5..9))),
+            vec![5..6, 8..9]
+        );
+    }
+
+    #[test]
+    fn test_capture_offsets_are_absolute() {
+        let regex = Regex::new(r"(?P<key>\w+)=(\w+)?").unwrap();
+        let mut text = Document::from("x; name=value; flag=");
+        text.insert(10, "_");
+
+        let found: Vec<Captures> = text.regex_find_iter(&regex, &(0..text.len())).collect();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].range(), 3..14);
+        assert_eq!(found[0].name("key"), Some(3..7));
+        assert_eq!(found[0].get(2), Some(8..14));
+        assert_eq!(found[1].range(), 16..21);
+        assert_eq!(found[1].get(2), None);
+        assert_eq!(found[1].group_count(), 3);
+    }
+
+    #[test]
+    fn test_spans_lines() {
+        for pattern in [r"foo\nbar", r"a\sb", r"(?s)a.b", r"[^x]+", r"(a|\n)*"] {
+            assert!(Regex::new(pattern).unwrap().spans_lines(), "{}", pattern);
+        }
+
+        for pattern in [r"foo bar", r"a.b", r"(?m)^\w+$", r"[^\n]+"] {
+            assert!(!Regex::new(pattern).unwrap().spans_lines(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_reverse_search() {
+        let regex = Regex::new(r"\d+").unwrap();
+        let text = fragmented("a1 b22 c333");
+
+        assert_eq!(
+            ranges(text.regex_rfind_iter(&regex, &(0..11))),
+            vec![8..11, 4..6, 1..2]
+        );
+        assert_eq!(
+            text.regex_rfind_iter(&regex, &(0..10))
+                .next()
+                .unwrap()
+                .range(),
+            8..10
+        );
+    }
+
+    #[test]
+    fn test_anchors_see_past_the_range() {
+        let regex = Regex::new(r"(?m)^\w+$").unwrap();
+        let text = fragmented("one two\nthree");
+
+        assert_eq!(ranges(text.regex_find_iter(&regex, &(4..13))), vec![8..13]);
+        assert!(text.regex_rfind_iter(&regex, &(0..11)).next().is_none());
+    }
+
+    #[test]
+    fn test_unicode_word_boundaries() {
+        let regex = Regex::new(r"\bcafé\b").unwrap();
+        let text = fragmented("cafés café écafé café");
+
+        assert_eq!(
+            ranges(text.regex_find_iter(&regex, &(0..text.len()))),
+            vec![7..12, 21..26]
+        );
+        assert_eq!(
+            ranges(text.regex_rfind_iter(&regex, &(0..text.len()))),
+            vec![21..26, 7..12]
+        );
+    }
+
+    #[test]
+    fn test_many_matches_in_non_ascii_text() {
+        let regex = Regex::new(r"\bwörd\b").unwrap();
+        let mut text = Document::from("é wörd ".repeat(5_000).as_str());
+        text.insert(0, "wörds ");
+
+        let forward = ranges(text.regex_find_iter(&regex, &(0..text.len())));
+        assert_eq!(forward.len(), 5_000);
+        assert_eq!(forward[0], 10..15);
+        assert_eq!(forward[4_999], 45_001..45_006);
+
+        let mut backward = ranges(text.regex_rfind_iter(&regex, &(0..text.len())));
+        backward.reverse();
+        assert_eq!(backward, forward);
+    }
+
+    #[test]
+    fn test_empty_matches() {
+        let regex = Regex::new(r"a*").unwrap();
+        let text = fragmented("baaéb");
+
+        assert_eq!(
+            ranges(text.regex_find_iter(&regex, &(0..6))),
+            expected(r"a*", "baaéb", 0..6)
+        );
+        assert_eq!(
+            ranges(text.regex_rfind_iter(&regex, &(0..6))),
+            vec![6..6, 5..5, 1..3, 0..0]
+        );
+    }
+
+    #[test]
+    fn test_matches_agree_with_regex() {
+        let source = "fn main() {\n    let x = 42;\n    println!(\"{}\", x);\n}\n";
+        let text = fragmented(source);
+        let patterns = [
+            r"\w+",
+            r"x",
+            r"(?m)^\s+",
+            r"\d*",
+            r"[{}]",
+            r"(?m)$",
+            r"let|print\w+",
+        ];
+
+        for pattern in patterns.iter() {
+            let regex = Regex::new(pattern).unwrap();
+
+            for range in [0..source.len(), 5..30, 12..12].iter() {
+                assert_eq!(
+                    ranges(text.regex_find_iter(&regex, range)),
+                    expected(pattern, source, range.clone()),
+                    "{}",
+                    pattern
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(Regex::new(r"(unclosed").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use super::diff::diff_text;
+use super::save::DiskState;
+use super::{Deleted, Document};
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+impl Document {
+    // Makes the text equal to `text` by editing only the parts that differ,
+    // one edit per difference, so positions elsewhere map through the
+    // changes as they would for typing.
+    //
+    // Returns each edit front to back: where its new text is now and what
+    // it replaced. To undo, go back to front, deleting the range and
+    // reinserting the `Deleted` at its start.
+    pub fn reload_from(&mut self, text: &str) -> Vec<(Range<usize>, Deleted)> {
+        let current = self.to_string();
+        let mut edits = vec![];
+
+        // Back to front, so each hunk's old offsets are still valid and its
+        // new text lands where the diff says.
+        for hunk in diff_text(&current, text).into_iter().rev() {
+            let deleted = self.replace(&hunk.old, &text[hunk.new.clone()]);
+            edits.push((hunk.new, deleted));
+        }
+
+        edits.reverse();
+
+        return edits;
+    }
+
+    // Re-reads the file the document was opened from or last saved to,
+    // decoding it as before, and applies it with `reload_from`. Afterwards
+    // the document matches the file, so it's unmodified and can be saved
+    // over it again.
+    pub fn reload(&mut self) -> io::Result<Vec<(Range<usize>, Deleted)>> {
+        let path = match &self.disk {
+            Some(disk) => disk.path().to_owned(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Document wasn't opened from a file",
+                ))
+            }
+        };
+
+        let (disk, bytes) = DiskState::read(&path)?;
+        let (decoded, _) = Document::from_bytes_with(&bytes, Arc::clone(&self.encoding), false)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+        let edits = self.reload_from(&decoded.to_string());
+        self.bom = decoded.bom;
+        self.disk = Some(disk);
+        self.mark_saved();
+
+        return Ok(edits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_reload_edits_only_differences() {
+        let mut text = Document::from("one\ntwo\nthree\n");
+        let version = text.version();
+        let edits = text.reload_from("one\n2\nthree\nfour\n");
+
+        assert_eq!(text.to_string(), "one\n2\nthree\nfour\n");
+        assert_eq!(
+            text.changes_since(version).map(|changes| changes.len()),
+            Some(2)
+        );
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].0, 4..5);
+        assert_eq!(edits[0].1.text(&text).to_string(), "two");
+
+        // Text that didn't change still comes from the original buffer.
+        assert_eq!(text.insertions.to_string(), "four\n2");
+    }
+
+    #[test]
+    fn test_undoing_a_reload() {
+        let mut text = Document::from("alpha beta gamma");
+        text.insert(5, ",");
+        let before = text.to_string();
+        let edits = text.reload_from("alpha: beta delta gamma!");
+
+        for (range, deleted) in edits.iter().rev() {
+            text.delete(range);
+            text.reinsert(range.start, deleted);
+        }
+
+        assert_eq!(text.to_string(), before);
+    }
+
+    #[test]
+    fn test_reload_without_changes() {
+        let mut text = Document::from("same");
+        let version = text.version();
+
+        assert!(text.reload_from("same").is_empty());
+        assert_eq!(text.version(), version);
+    }
+
+    #[test]
+    fn test_reload_from_disk() {
+        let path = std::env::temp_dir().join(format!("reload-{}", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        fs::write(&path, "first\nsecond\n").unwrap();
+        assert!(text.save(&path).is_err());
+
+        text.reload().unwrap();
+        assert_eq!(text.to_string(), "first\nsecond\n");
+
+        text.insert(0, "zeroth\n");
+        text.save(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "zeroth\nfirst\nsecond\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
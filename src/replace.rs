@@ -0,0 +1,471 @@
+use super::fragment::Fragment;
+use super::{Captures, Deleted, Document, Regex};
+use std::{error::Error, fmt, ops::Range};
+
+// What `replace_all` looks for.
+#[derive(Clone, Copy)]
+pub enum Pattern<'a> {
+    Literal(&'a str),
+    Regex(&'a Regex),
+}
+
+// One proposed edit from a dry run.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Replacement {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+// Why `apply_replacements` refused a batch. Nothing is changed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReplaceError {
+    // Two replacements cover some of the same text.
+    // (first_range, second_range)
+    Overlapping(Range<usize>, Range<usize>),
+    // A range ends before it starts or runs past the end of the text.
+    // (range)
+    InvalidRange(Range<usize>),
+}
+
+// A piece of a replacement template.
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Group(usize),
+    Named(String),
+}
+
+// A match and the pieces its replacement is built from.
+type Edit = (Range<usize>, Vec<Piece>);
+
+// Where the bytes of one replacement come from: a template part, or text
+// captured from the document.
+enum Piece {
+    Part(usize),
+    Copy(Range<usize>),
+}
+
+impl<'a> From<&'a str> for Pattern<'a> {
+    fn from(literal: &'a str) -> Self {
+        return Pattern::Literal(literal);
+    }
+}
+
+impl<'a> From<&'a Regex> for Pattern<'a> {
+    fn from(regex: &'a Regex) -> Self {
+        return Pattern::Regex(regex);
+    }
+}
+
+impl fmt::Display for ReplaceError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            ReplaceError::Overlapping(first, second) => {
+                write!(fmt, "Replacements at {:?} and {:?} overlap", first, second)
+            }
+            ReplaceError::InvalidRange(range) => {
+                write!(fmt, "Replacement range {:?} is out of bounds", range)
+            }
+        };
+    }
+}
+
+impl Error for ReplaceError {}
+
+// Splits a template into text and `$1`, `$name` or `${name}` references,
+// following the `regex` crate's rules. `$$` is a literal dollar sign.
+fn parse_template(template: &str) -> Vec<Part> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut rest = template;
+
+    while let Some(dollar) = rest.find('$') {
+        text.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+
+        let (name, skip) = if rest.starts_with('$') {
+            text.push('$');
+            rest = &rest[1..];
+            continue;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(close) => (&braced[..close], close + 2),
+                None => ("", 0),
+            }
+        } else {
+            let length = rest
+                .find(|character: char| !(character.is_ascii_alphanumeric() || character == '_'))
+                .unwrap_or(rest.len());
+
+            (&rest[..length], length)
+        };
+
+        if name.is_empty() {
+            text.push('$');
+            continue;
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(std::mem::take(&mut text)));
+        }
+
+        parts.push(match name.parse() {
+            Ok(index) => Part::Group(index),
+            Err(_) => Part::Named(name.to_owned()),
+        });
+        rest = &rest[skip..];
+    }
+
+    text.push_str(rest);
+
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+
+    return parts;
+}
+
+impl Document {
+    // Replaces every match of `pattern` in one edit and returns how many
+    // there were. Regex templates can refer to capture groups as `$1`,
+    // `$name` or `${name}`; literal templates are used as is. To review the
+    // edits first, or to be able to undo them, use `replace_all_dry_run`
+    // and `apply_replacements` instead.
+    pub fn replace_all<'p>(&mut self, pattern: impl Into<Pattern<'p>>, template: &str) -> usize {
+        let (parts, edits) = self.plan_replacements(pattern.into(), template);
+        let count = edits.len();
+
+        // Each template part is stored once and shared by every match.
+        let mut stored: Vec<Option<Fragment>> = parts.iter().map(|_| None).collect();
+        let mut replacements = vec![];
+
+        for (range, pieces) in edits {
+            let mut fragments = vec![];
+
+            for piece in pieces {
+                match piece {
+                    Piece::Copy(range) => fragments.extend(self.fragments.slice_fragments(&range)),
+                    Piece::Part(index) => {
+                        if stored[index].is_none() {
+                            if let Part::Text(text) = &parts[index] {
+                                stored[index] = Some(self.create_insertion_fragment(text));
+                            }
+                        }
+
+                        fragments.extend(stored[index].clone());
+                    }
+                }
+            }
+
+            replacements.push((range, fragments));
+        }
+
+        self.splice_replacements(replacements);
+
+        return count;
+    }
+
+    // The edits `replace_all` would make, without making them.
+    pub fn replace_all_dry_run<'p>(
+        &self,
+        pattern: impl Into<Pattern<'p>>,
+        template: &str,
+    ) -> Vec<Replacement> {
+        let (parts, edits) = self.plan_replacements(pattern.into(), template);
+
+        return edits
+            .into_iter()
+            .map(|(range, pieces)| {
+                let mut text = String::new();
+
+                for piece in pieces {
+                    match piece {
+                        Piece::Copy(range) => self.chunks(&range).for_each(|chunk| text += chunk),
+                        Piece::Part(index) => {
+                            if let Part::Text(part) = &parts[index] {
+                                text += part;
+                            }
+                        }
+                    }
+                }
+
+                Replacement { range, text }
+            })
+            .collect();
+    }
+
+    // Makes a batch of edits, such as the ones from a dry run, as a single
+    // splice. They may come in any order but must not overlap; several
+    // empty ranges at one offset are inserted in the order given.
+    //
+    // Returns where the edited text is now and what it replaced, from the
+    // first edit to the end of the last. To undo, delete the range and
+    // reinsert the `Deleted` at its start.
+    pub fn apply_replacements(
+        &mut self,
+        replacements: &[Replacement],
+    ) -> Result<(Range<usize>, Deleted), ReplaceError> {
+        let mut sorted: Vec<&Replacement> = replacements.iter().collect();
+        sorted.sort_by_key(|replacement| (replacement.range.start, replacement.range.end));
+
+        for replacement in &sorted {
+            let range = &replacement.range;
+
+            if range.start > range.end || range.end > self.len() {
+                return Err(ReplaceError::InvalidRange(range.clone()));
+            }
+        }
+
+        for pair in sorted.windows(2) {
+            if pair[1].range.start < pair[0].range.end {
+                return Err(ReplaceError::Overlapping(
+                    pair[0].range.clone(),
+                    pair[1].range.clone(),
+                ));
+            }
+        }
+
+        let replacements = sorted
+            .into_iter()
+            .map(|replacement| {
+                let pieces = match replacement.text.is_empty() {
+                    true => vec![],
+                    false => vec![self.create_insertion_fragment(&replacement.text)],
+                };
+
+                (replacement.range.clone(), pieces)
+            })
+            .collect();
+
+        let before = self.len();
+        let deleted = self.splice_replacements(replacements);
+        let end = deleted.start() + deleted.len() + self.len() - before;
+
+        return Ok((deleted.start()..end, deleted));
+    }
+
+    // Rebuilds everything between the first and last edit in one pass, so
+    // the fragments after each edit are re-keyed once rather than per edit.
+    // The edits must be sorted and must not overlap.
+    pub(crate) fn splice_replacements(
+        &mut self,
+        replacements: Vec<(Range<usize>, Vec<Fragment>)>,
+    ) -> Deleted {
+        let (start, end) = match (replacements.first(), replacements.last()) {
+            (Some((first, _)), Some((last, _))) => (first.start, last.end.max(first.start)),
+            _ => return Deleted::new(0, vec![]),
+        };
+
+        let mut pieces = vec![];
+        let mut cursor = start;
+
+        for (range, fragments) in replacements {
+            assert!(
+                range.start >= cursor && range.start <= range.end,
+                "Replacements overlap or are out of order"
+            );
+
+            pieces.extend(self.fragments.slice_fragments(&(cursor..range.start)));
+            pieces.extend(fragments);
+            cursor = range.end;
+        }
+
+        let deleted = self.splice(&(start..end), pieces);
+        self.after_edit();
+
+        return deleted;
+    }
+
+    // Finds the matches and works out where each replacement's bytes come
+    // from. Captured text is referenced by range so it can be shared.
+    fn plan_replacements(&self, pattern: Pattern, template: &str) -> (Vec<Part>, Vec<Edit>) {
+        let range = 0..self.len();
+
+        let parts = match pattern {
+            Pattern::Literal(_) if template.is_empty() => vec![],
+            Pattern::Literal(_) => vec![Part::Text(template.to_owned())],
+            Pattern::Regex(_) => parse_template(template),
+        };
+
+        let matches: Vec<(Range<usize>, Option<Captures>)> = match pattern {
+            Pattern::Literal(literal) => self
+                .find_iter(literal, &range)
+                .map(|found| (found, None))
+                .collect(),
+            Pattern::Regex(regex) => self
+                .regex_find_iter(regex, &range)
+                .map(|captures| (captures.range(), Some(captures)))
+                .collect(),
+        };
+
+        let edits = matches
+            .into_iter()
+            .map(|(range, captures)| {
+                let pieces = parts
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, part)| {
+                        let group = match (part, &captures) {
+                            (Part::Text(_), _) => return Some(Piece::Part(index)),
+                            (Part::Group(group), Some(captures)) => captures.get(*group),
+                            (Part::Named(name), Some(captures)) => captures.name(name),
+                            (_, None) => None,
+                        };
+
+                        group.filter(|group| !group.is_empty()).map(Piece::Copy)
+                    })
+                    .collect();
+
+                (range, pieces)
+            })
+            .collect();
+
+        return (parts, edits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(
+            parse_template("a$1b${2}c$name ${x}$$d$"),
+            vec![
+                Part::Text("a".to_owned()),
+                Part::Named("1b".to_owned()),
+                Part::Group(2),
+                Part::Text("c".to_owned()),
+                Part::Named("name".to_owned()),
+                Part::Text(" ".to_owned()),
+                Part::Named("x".to_owned()),
+                Part::Text("$d$".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_all_literal() {
+        let mut text = Document::from("one two one two one");
+        text.insert(4, "-");
+
+        assert_eq!(text.replace_all("one", "1"), 3);
+        assert_eq!(text.to_string(), "1 -two 1 two 1");
+        // The replacement text is stored once for all three matches.
+        assert_eq!(text.insertions.len(), 2);
+    }
+
+    #[test]
+    fn test_replace_all_with_captures() {
+        let regex = Regex::new(r"(?P<key>\w+)=(\w+)").unwrap();
+        let mut text = Document::from("a=1, bb=22");
+
+        assert_eq!(text.replace_all(&regex, "$2:${key}$$"), 2);
+        assert_eq!(text.to_string(), "1:a$, 22:bb$");
+        // Captured text is shared, not copied.
+        assert_eq!(text.insertions.to_string(), ":$");
+    }
+
+    #[test]
+    fn test_replace_all_without_matches() {
+        let mut text = Document::from("text");
+
+        let version = text.version();
+
+        assert_eq!(text.replace_all("none", "x"), 0);
+        assert_eq!(text.to_string(), "text");
+        assert_eq!(text.version(), version);
+    }
+
+    #[test]
+    fn test_replace_all_empty_matches() {
+        let regex = Regex::new(r"x*").unwrap();
+        let mut text = Document::from("axb");
+
+        assert_eq!(text.replace_all(&regex, "-"), 3);
+        assert_eq!(text.to_string(), "-a-b-");
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let regex = Regex::new(r"(\d+)").unwrap();
+        let text = Document::from("a1 b22");
+
+        assert_eq!(
+            text.replace_all_dry_run(&regex, "<$1>"),
+            vec![
+                Replacement {
+                    range: 1..2,
+                    text: "<1>".to_owned()
+                },
+                Replacement {
+                    range: 4..6,
+                    text: "<22>".to_owned()
+                },
+            ]
+        );
+        assert_eq!(text.to_string(), "a1 b22");
+    }
+
+    #[test]
+    fn test_apply_replacements_is_one_edit() {
+        let mut text = Document::from("a1 b22 c");
+        let edits = text.replace_all_dry_run("2", "two");
+        let version = text.version();
+        let (range, deleted) = text.apply_replacements(&edits).unwrap();
+
+        assert_eq!(text.to_string(), "a1 btwotwo c");
+        assert_eq!(text.changes_since(version).unwrap().len(), 1);
+        assert_eq!(range, 4..10);
+        assert_eq!(deleted.text(&text).to_string(), "22");
+    }
+
+    #[test]
+    fn test_replace_all_is_one_edit() {
+        let mut text = Document::from("one two one two");
+        let version = text.version();
+
+        assert_eq!(text.replace_all("two", "2"), 2);
+        assert_eq!(text.to_string(), "one 2 one 2");
+        assert_eq!(text.changes_since(version).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_undo_replacements() {
+        let mut text = Document::from("one two one two");
+        let edits = text.replace_all_dry_run("two", "2");
+        let (range, deleted) = text.apply_replacements(&edits).unwrap();
+
+        assert_eq!(text.to_string(), "one 2 one 2");
+        assert_eq!(range, 4..11);
+
+        text.delete(&range);
+        text.reinsert(range.start, &deleted);
+        assert_eq!(text.to_string(), "one two one two");
+    }
+
+    #[test]
+    fn test_apply_replacements_in_any_order() {
+        let mut text = Document::from("a b c");
+        let replacement = |range: Range<usize>, text: &str| Replacement {
+            range,
+            text: text.to_owned(),
+        };
+
+        let edits = [replacement(4..5, "C"), replacement(0..1, "A")];
+        text.apply_replacements(&edits).unwrap();
+        assert_eq!(text.to_string(), "A b C");
+
+        let overlapping = [replacement(0..3, "x"), replacement(2..4, "y")];
+        assert_eq!(
+            text.apply_replacements(&overlapping).err(),
+            Some(ReplaceError::Overlapping(0..3, 2..4))
+        );
+        assert_eq!(
+            text.apply_replacements(&[replacement(3..9, "z")]).err(),
+            Some(ReplaceError::InvalidRange(3..9))
+        );
+        assert_eq!(text.to_string(), "A b C");
+    }
+}
@@ -0,0 +1,546 @@
+use super::crc32::{crc32, Crc32};
+use super::Document;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use std::{error::Error, fmt, process};
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    // The file was changed by something else since it was opened or last
+    // saved. Saving again with `save_forced` overwrites it anyway.
+    // (path)
+    ModifiedOnDisk(PathBuf),
+}
+
+// Tells apart the temporary files of saves made by this process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// What the file looked like when the document last read or wrote it.
+pub struct DiskState {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    size: u64,
+    checksum: u32,
+}
+
+// Passes writes through while keeping a running checksum and size.
+struct Checksummed<W> {
+    inner: W,
+    crc: Crc32,
+    size: u64,
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            SaveError::Io(error) => write!(fmt, "Failed to save document: {}", error),
+            SaveError::ModifiedOnDisk(path) => write!(
+                fmt,
+                "{} was changed by another program since it was opened",
+                path.display()
+            ),
+        };
+    }
+}
+
+impl Error for SaveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            SaveError::Io(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(error: io::Error) -> Self {
+        return SaveError::Io(error);
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(bytes)?;
+        self.crc.update(&bytes[..count]);
+        self.size += count as u64;
+
+        return Ok(count);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+impl DiskState {
+    // Reads a whole file, noting what it looked like.
+    pub fn read(path: &Path) -> io::Result<(DiskState, Vec<u8>)> {
+        let mut file = File::open(path)?;
+        let modified = file.metadata()?.modified().ok();
+
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let disk = DiskState {
+            path: path.to_owned(),
+            modified,
+            size: bytes.len() as u64,
+            checksum: crc32(&bytes),
+        };
+
+        return Ok((disk, bytes));
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    // Whether the file still holds what the document last saw. Size and
+    // modification time settle it when they match; otherwise the contents
+    // are compared, so a file that was only touched doesn't count.
+    fn is_current(&self) -> io::Result<bool> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            // Deleted behind our back. Writing it again loses nothing.
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(error) => return Err(error),
+        };
+
+        let metadata = file.metadata()?;
+
+        if metadata.len() != self.size {
+            return Ok(false);
+        }
+
+        if metadata.modified().ok() == self.modified && self.modified.is_some() {
+            return Ok(true);
+        }
+
+        let mut crc = Crc32::new();
+        let mut chunk = vec![0; 64 * 1024];
+
+        loop {
+            match file.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(count) => crc.update(&chunk[..count]),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        return Ok(crc.finish() == self.checksum);
+    }
+}
+
+impl Document {
+    // Loads a file, detecting its encoding as `from_bytes` does, and
+    // remembers its size, modification time and checksum so `save` can tell
+    // if something else changes it.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Document> {
+        let (disk, bytes) = DiskState::read(path.as_ref())?;
+        let mut document = Document::from_bytes(&bytes)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        document.disk = Some(disk);
+
+        return Ok(document);
+    }
+
+    // Writes the document in its encoding without ever leaving a half
+    // written file: the text goes to a temporary file in the same directory,
+    // which is synced and then renamed over `path`, keeping its permissions.
+    //
+    // Refuses if the document came from `path` and the file has changed
+    // since it was opened or last saved.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveError> {
+        let path = path.as_ref();
+
+        if let Some(disk) = self
+            .disk
+            .as_ref()
+            .filter(|disk| same_file(&disk.path, path))
+        {
+            if !disk.is_current()? {
+                return Err(SaveError::ModifiedOnDisk(path.to_owned()));
+            }
+        }
+
+        return self.save_forced(path).map_err(SaveError::Io);
+    }
+
+    // Like `save`, but overwrites the file even if it changed on disk.
+    pub fn save_forced<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let link = path.as_ref();
+        // The rename must land on the file a symlink points at, or it would
+        // replace the link itself.
+        let target = resolve_symlinks(link)?;
+        let path = target.as_path();
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Save path has no file name")
+        })?;
+
+        let (temp_path, file) = create_temp_file(directory, name)?;
+
+        let (size, checksum) = match self.write_temp_file(file, &temp_path, path) {
+            Ok(written) => written,
+            Err(error) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(error);
+            }
+        };
+
+        if let Err(error) = fs::rename(&temp_path, path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(error);
+        }
+
+        sync_directory(directory);
+
+        // Remembered as given, so saving through the link again is checked.
+        self.disk = Some(DiskState {
+            path: link.to_owned(),
+            modified: fs::metadata(path)?.modified().ok(),
+            size,
+            checksum,
+        });
+        self.mark_saved();
+
+        return Ok(());
+    }
+
+    // Returns the size and checksum of what was written.
+    fn write_temp_file(
+        &self,
+        file: File,
+        temp_path: &Path,
+        target: &Path,
+    ) -> io::Result<(u64, u32)> {
+        let mut writer = Checksummed {
+            inner: BufWriter::new(file),
+            crc: Crc32::new(),
+            size: 0,
+        };
+        self.write_encoded_to(&mut writer)?;
+
+        let file = writer
+            .inner
+            .into_inner()
+            .map_err(|error| error.into_error())?;
+        file.sync_all()?;
+
+        match fs::metadata(target) {
+            Ok(metadata) => fs::set_permissions(temp_path, metadata.permissions())?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+
+        return Ok((writer.size, writer.crc.finish()));
+    }
+}
+
+// Creates `.<name>.<pid>.<count>.tmp` next to the file being saved. Names
+// left behind by a crash, or taken by another save, are skipped.
+fn create_temp_file(directory: &Path, name: &std::ffi::OsStr) -> io::Result<(PathBuf, File)> {
+    for _ in 0..100 {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = directory.join(temp_name);
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    return Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Couldn't find an unused temporary file name",
+    ));
+}
+
+// Whether two paths name the same file, e.g. `./a.txt` and `a.txt`, or a
+// symlink and its target. Paths to files that don't exist are only equal
+// if they're spelled the same.
+fn same_file(first: &Path, second: &Path) -> bool {
+    if first == second {
+        return true;
+    }
+
+    return match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => false,
+    };
+}
+
+// Follows symlinks to the file they name. Done by hand rather than with
+// `fs::canonicalize` so a link to a file that doesn't exist yet still
+// resolves.
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_owned();
+
+    // The same limit Linux puts on nested links.
+    for _ in 0..40 {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                // Relative targets are relative to the link's directory.
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            Ok(_) => return Ok(path),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(path),
+            Err(error) => return Err(error),
+        }
+    }
+
+    return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Too many levels of symbolic links",
+    ));
+}
+
+// Makes the rename itself durable where the platform allows it.
+fn sync_directory(directory: &Path) {
+    #[cfg(unix)]
+    {
+        if let Ok(directory) = File::open(directory) {
+            let _ = directory.sync_all();
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = directory;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("save-{}-{}", process::id(), name));
+    }
+
+    #[test]
+    fn test_open_edit_save() {
+        let path = temp_path("edit");
+        fs::write(&path, "hello\n").unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        text.insert(5, " world");
+        assert!(text.is_modified());
+        text.save(&path).unwrap();
+        assert!(!text.is_modified());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world\n");
+
+        // Saving again is fine; the document knows what it wrote.
+        text.insert(0, "> ");
+        text.save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "> hello world\n");
+
+        let leftovers = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.contains(&format!("save-{}-edit.", process::id()))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_to_overwrite_changes() {
+        let path = temp_path("changed");
+        fs::write(&path, "original").unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        text.insert(0, "edited ");
+        fs::write(&path, "someone else's").unwrap();
+
+        assert!(matches!(
+            text.save(&path),
+            Err(SaveError::ModifiedOnDisk(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "someone else's");
+
+        text.save_forced(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "edited original");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_changes_are_caught_through_another_path() {
+        let path = temp_path("other-path");
+        fs::write(&path, "original").unwrap();
+        let mut text = Document::open(&path).unwrap();
+        fs::write(&path, "someone else's").unwrap();
+
+        let other = path
+            .parent()
+            .unwrap()
+            .join(".")
+            .join(path.file_name().unwrap());
+        assert!(matches!(
+            text.save(&other),
+            Err(SaveError::ModifiedOnDisk(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "someone else's");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stale_temp_files_are_skipped() {
+        let path = temp_path("stale");
+        fs::write(&path, "text").unwrap();
+        let mut text = Document::open(&path).unwrap();
+
+        // Every name the next few saves could pick is already taken.
+        let next = TEMP_COUNTER.load(Ordering::Relaxed);
+        let stale: Vec<PathBuf> = (next..next + 20)
+            .map(|count| {
+                let name = format!(
+                    ".save-{}-stale.{}.{}.tmp",
+                    process::id(),
+                    process::id(),
+                    count
+                );
+                path.with_file_name(name)
+            })
+            .collect();
+        for stale in &stale {
+            fs::write(stale, "left over").unwrap();
+        }
+
+        text.insert(4, "!");
+        text.save(&path).unwrap();
+        text.insert(5, "?");
+        text.save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "text!?");
+
+        for stale in &stale {
+            assert_eq!(fs::read_to_string(stale).unwrap(), "left over");
+            fs::remove_file(stale).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_same_size_changes_are_caught() {
+        let path = temp_path("same-size");
+        fs::write(&path, "aaaa").unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        fs::write(&path, "bbbb").unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        assert!(text.save(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_touched_files_can_be_saved() {
+        let path = temp_path("touched");
+        fs::write(&path, "text").unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        text.insert(4, "!");
+        text.save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "text!");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_are_kept() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("permissions");
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        text.insert(text.len(), "true\n");
+        text.save(&path).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o751);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_saving_through_a_symlink() {
+        let target = temp_path("link-target");
+        let link = temp_path("link");
+        fs::write(&target, "text").unwrap();
+        let _ = fs::remove_file(&link);
+        // Relative, as links usually are.
+        std::os::unix::fs::symlink(target.file_name().unwrap(), &link).unwrap();
+
+        let mut text = Document::open(&link).unwrap();
+        text.insert(4, "!");
+        text.save(&link).unwrap();
+        text.insert(5, "?");
+        text.save(&link).unwrap();
+
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "text!?");
+
+        // The link is still watched for changes made through it.
+        fs::write(&target, "changed elsewhere").unwrap();
+        assert!(matches!(
+            text.save(&link),
+            Err(SaveError::ModifiedOnDisk(_))
+        ));
+
+        fs::remove_file(&link).unwrap();
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn test_encoding_is_kept() {
+        let path = temp_path("encoding");
+        fs::write(&path, [0xFF, 0xFE, b'h', 0, b'i', 0]).unwrap();
+
+        let mut text = Document::open(&path).unwrap();
+        text.insert(2, "!");
+        text.save(&path).unwrap();
+
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            vec![0xFF, 0xFE, b'h', 0, b'i', 0, b'!', 0]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
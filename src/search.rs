@@ -0,0 +1,559 @@
+use super::{Document, Regex};
+use std::ops::Range;
+
+// Boyer-Moore-Horspool shift tables for one pattern, in both directions.
+struct Matcher {
+    pattern: Vec<u8>,
+    // How far to slide right, keyed by the byte under the window's end.
+    forward: [usize; 256],
+    // How far to slide left, keyed by the byte under the window's start.
+    backward: [usize; 256],
+}
+
+// Non-overlapping matches of a literal pattern, found lazily by streaming
+// through the fragments. See `Document::find_iter`.
+pub struct Matches<'a> {
+    matcher: Matcher,
+    chunks: Box<dyn Iterator<Item = &'a str> + 'a>,
+    chunk: &'a [u8],
+    chunk_start: usize,
+    position: usize,
+    // The last bytes of the previous chunks followed by the first bytes of
+    // the current one, so matches straddling a boundary aren't missed.
+    junction: Vec<u8>,
+    junction_start: usize,
+    junction_position: usize,
+    // Up to `pattern.len() - 1` bytes from just before the current chunk.
+    tail: Vec<u8>,
+    // End of the last match, to keep matches from overlapping.
+    next_allowed: usize,
+}
+
+// The "Match case" and "Whole word" toggles of a search panel.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SearchOptions {
+    pub match_case: bool,
+    // Only matches with no letter, digit or underscore on either side.
+    pub whole_word: bool,
+}
+
+// A literal search with its options applied, compiled once so it can be
+// run again after every edit.
+pub struct SearchQuery {
+    pattern: String,
+    options: SearchOptions,
+    // Ignoring case is left to a regex, which folds case as it streams.
+    folded: Option<Regex>,
+}
+
+// Matches of a `SearchQuery`. See `Document::find_query_iter`.
+pub struct QueryMatches<'a> {
+    document: &'a Document,
+    query: &'a SearchQuery,
+    candidates: Box<dyn Iterator<Item = Range<usize>> + 'a>,
+    end: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            match_case: true,
+            whole_word: false,
+        }
+    }
+}
+
+impl SearchQuery {
+    pub fn new(pattern: &str, options: SearchOptions) -> Self {
+        let folded = match options.match_case || pattern.is_empty() {
+            true => None,
+            false => {
+                // Simple case folding, as `(?i)` does in the `regex` crate.
+                let escaped = format!("(?i){}", regex_syntax::escape(pattern));
+                Some(Regex::new(&escaped).expect("Escaped pattern is a valid regex"))
+            }
+        };
+
+        SearchQuery {
+            pattern: pattern.to_owned(),
+            options,
+            folded,
+        }
+    }
+
+    // Matches before the whole word check.
+    fn candidates<'a>(
+        &'a self,
+        document: &'a Document,
+        range: &Range<usize>,
+    ) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
+        return match &self.folded {
+            Some(regex) => Box::new(
+                document
+                    .regex_find_iter(regex, range)
+                    .map(|captures| captures.range()),
+            ),
+            None => Box::new(document.find_iter(&self.pattern, range)),
+        };
+    }
+
+    // The last candidate ending at or before `end`.
+    fn last_candidate(&self, document: &Document, end: usize) -> Option<Range<usize>> {
+        return match &self.folded {
+            Some(regex) => document
+                .regex_rfind_iter(regex, &(0..end))
+                .next()
+                .map(|captures| captures.range()),
+            None => document.rfind(&self.pattern, end),
+        };
+    }
+
+    fn accepts(&self, document: &Document, found: &Range<usize>) -> bool {
+        return !self.options.whole_word || document.is_whole_word(found);
+    }
+}
+
+impl<'a> Iterator for QueryMatches<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let found = self.candidates.next()?;
+
+            if self.query.accepts(self.document, &found) {
+                return Some(found);
+            }
+
+            // A rejected candidate may hide one that overlaps it, so search
+            // again from its second character.
+            let skip = self
+                .document
+                .char_after(found.start)
+                .map_or(1, char::len_utf8);
+            let range = found.start + skip..self.end;
+            self.candidates = self.query.candidates(self.document, &range);
+        }
+    }
+}
+
+impl Matcher {
+    fn new(pattern: &[u8]) -> Self {
+        let length = pattern.len();
+        let mut forward = [length; 256];
+        let mut backward = [length; 256];
+
+        for (index, byte) in pattern[..length.saturating_sub(1)].iter().enumerate() {
+            forward[*byte as usize] = length - 1 - index;
+        }
+
+        for (index, byte) in pattern.iter().enumerate().skip(1).rev() {
+            backward[*byte as usize] = index;
+        }
+
+        Matcher {
+            pattern: pattern.to_vec(),
+            forward,
+            backward,
+        }
+    }
+
+    // Bytes of context a chunk needs from its neighbours.
+    fn overlap(&self) -> usize {
+        return self.pattern.len().saturating_sub(1);
+    }
+
+    // The first occurrence starting at or after `from`.
+    fn find_in(&self, haystack: &[u8], from: usize) -> Option<usize> {
+        let length = self.pattern.len();
+        let mut position = from;
+
+        while position + length <= haystack.len() {
+            let window = &haystack[position..position + length];
+
+            if window == &self.pattern[..] {
+                return Some(position);
+            }
+
+            position += self.forward[window[length - 1] as usize];
+        }
+
+        return None;
+    }
+
+    // The last occurrence, overlapping ones included.
+    fn rfind_in(&self, haystack: &[u8]) -> Option<usize> {
+        let length = self.pattern.len();
+        let mut position = haystack.len().checked_sub(length)?;
+
+        loop {
+            let window = &haystack[position..position + length];
+
+            if window == &self.pattern[..] {
+                return Some(position);
+            }
+
+            position = position.checked_sub(self.backward[window[0] as usize])?;
+        }
+    }
+}
+
+impl<'a> Iterator for Matches<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = self.matcher.pattern.len();
+
+        // An empty pattern would match everywhere, so it matches nowhere.
+        if length == 0 {
+            return None;
+        }
+
+        loop {
+            let found =
+                if let Some(index) = self.matcher.find_in(&self.junction, self.junction_position) {
+                    self.junction_position = index + 1;
+                    self.junction_start + index
+                } else if let Some(index) = self.matcher.find_in(self.chunk, self.position) {
+                    self.junction_position = self.junction.len();
+                    self.position = index + 1;
+                    self.chunk_start + index
+                } else {
+                    self.advance()?;
+                    continue;
+                };
+
+            if found >= self.next_allowed {
+                self.next_allowed = found + length;
+                return Some(found..found + length);
+            }
+        }
+    }
+}
+
+impl<'a> Matches<'a> {
+    // Moves on to the next chunk and builds the junction into it.
+    fn advance(&mut self) -> Option<()> {
+        let overlap = self.matcher.overlap();
+
+        if self.chunk.len() >= overlap {
+            self.tail.clear();
+            self.tail
+                .extend_from_slice(&self.chunk[self.chunk.len() - overlap..]);
+        } else {
+            self.tail.extend_from_slice(self.chunk);
+            let excess = self.tail.len().saturating_sub(overlap);
+            self.tail.drain(..excess);
+        }
+
+        let next = self.chunks.next()?.as_bytes();
+        self.chunk_start += self.chunk.len();
+        self.chunk = next;
+        self.position = 0;
+
+        self.junction.clear();
+        self.junction.extend_from_slice(&self.tail);
+        self.junction
+            .extend_from_slice(&next[..next.len().min(overlap)]);
+        self.junction_start = self.chunk_start - self.tail.len();
+        self.junction_position = 0;
+
+        return Some(());
+    }
+}
+
+impl Document {
+    // The first match of `pattern` starting at or after `from`. An empty
+    // pattern never matches.
+    pub fn find(&self, pattern: &str, from: usize) -> Option<Range<usize>> {
+        return self.find_iter(pattern, &(from..self.len())).next();
+    }
+
+    // The last match of `pattern` that ends at or before `end`, for "find
+    // previous". Walks the fragments back to front.
+    pub fn rfind(&self, pattern: &str, end: usize) -> Option<Range<usize>> {
+        if pattern.is_empty() {
+            return None;
+        }
+
+        let matcher = Matcher::new(pattern.as_bytes());
+        let overlap = matcher.overlap();
+        // Up to `overlap` bytes from just after the current chunk.
+        let mut head: Vec<u8> = vec![];
+        let mut chunk_end = end.min(self.len());
+
+        for chunk in self.chunks(&(0..end)).rev() {
+            let bytes = chunk.as_bytes();
+            let edge = bytes.len().min(overlap);
+            let mut junction = bytes[bytes.len() - edge..].to_vec();
+            junction.extend_from_slice(&head);
+
+            if let Some(index) = matcher.rfind_in(&junction) {
+                let start = chunk_end - edge + index;
+                return Some(start..start + pattern.len());
+            }
+
+            let chunk_start = chunk_end - bytes.len();
+
+            if let Some(index) = matcher.rfind_in(bytes) {
+                let start = chunk_start + index;
+                return Some(start..start + pattern.len());
+            }
+
+            let mut next_head = bytes[..edge].to_vec();
+            next_head.extend_from_slice(&head);
+            next_head.truncate(overlap);
+            head = next_head;
+            chunk_end = chunk_start;
+        }
+
+        return None;
+    }
+
+    // Like `find`, with case folding and whole word checks as configured.
+    pub fn find_query(&self, query: &SearchQuery, from: usize) -> Option<Range<usize>> {
+        return self.find_query_iter(query, &(from..self.len())).next();
+    }
+
+    // Like `rfind`, with case folding and whole word checks as configured.
+    pub fn rfind_query(&self, query: &SearchQuery, end: usize) -> Option<Range<usize>> {
+        let mut end = end.min(self.len());
+
+        loop {
+            let found = query.last_candidate(self, end)?;
+
+            if query.accepts(self, &found) {
+                return Some(found);
+            }
+
+            // Look for one ending a character earlier, which may overlap.
+            end = found.end - self.char_before(found.end).map_or(1, char::len_utf8);
+        }
+    }
+
+    pub fn find_query_iter<'a>(
+        &'a self,
+        query: &'a SearchQuery,
+        range: &Range<usize>,
+    ) -> QueryMatches<'a> {
+        return QueryMatches {
+            document: self,
+            query,
+            candidates: query.candidates(self, range),
+            end: range.end.min(self.len()),
+        };
+    }
+
+    // Whether the characters on both sides of a range, which may be in
+    // other fragments, are not part of a word.
+    fn is_whole_word(&self, range: &Range<usize>) -> bool {
+        let is_word = |character: char| character.is_alphanumeric() || character == '_';
+
+        return !self.char_before(range.start).is_some_and(is_word)
+            && !self.char_after(range.end).is_some_and(is_word);
+    }
+
+    fn char_after(&self, at: usize) -> Option<char> {
+        let bytes: Vec<u8> = self.bytes_from(at).take(4).map(|(_, byte)| byte).collect();
+
+        return (1..=bytes.len())
+            .find_map(|length| std::str::from_utf8(&bytes[..length]).ok())
+            .and_then(|text| text.chars().next());
+    }
+
+    fn char_before(&self, at: usize) -> Option<char> {
+        let mut bytes: Vec<u8> = self
+            .bytes_before(at)
+            .take(4)
+            .map(|(_, byte)| byte)
+            .collect();
+        bytes.reverse();
+
+        return (1..=bytes.len())
+            .find_map(|length| std::str::from_utf8(&bytes[bytes.len() - length..]).ok())
+            .and_then(|text| text.chars().next_back());
+    }
+
+    // Every non-overlapping match of `pattern` inside a byte range, front to
+    // back. Nothing is copied beyond a few bytes around fragment boundaries.
+    pub fn find_iter(&self, pattern: &str, range: &Range<usize>) -> Matches<'_> {
+        let start = range.start.min(self.len());
+
+        return Matches {
+            matcher: Matcher::new(pattern.as_bytes()),
+            chunks: Box::new(self.chunks(range)),
+            chunk: &[],
+            chunk_start: start,
+            position: 0,
+            junction: vec![],
+            junction_start: start,
+            junction_position: 0,
+            tail: vec![],
+            next_allowed: 0,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a document with a fragment boundary between every character.
+    fn fragmented(text: &str) -> Document {
+        let mut document = Document::new();
+
+        for character in text.chars().rev() {
+            document.insert(0, &character.to_string());
+        }
+
+        return document;
+    }
+
+    fn starts(matches: Matches) -> Vec<usize> {
+        return matches.map(|found| found.start).collect();
+    }
+
+    #[test]
+    fn test_find() {
+        let text = Document::from("one two one two");
+
+        assert_eq!(text.find("two", 0), Some(4..7));
+        assert_eq!(text.find("two", 5), Some(12..15));
+        assert_eq!(text.find("three", 0), None);
+        assert_eq!(text.find("", 0), None);
+    }
+
+    #[test]
+    fn test_find_across_fragments() {
+        let mut text = Document::from("needle");
+        text.insert(3, "d");
+        text.delete(&(3..4));
+        text.insert(0, "hay ");
+
+        assert_eq!(text.find("needle", 0), Some(4..10));
+        assert_eq!(fragmented("haystack").find("stack", 0), Some(3..8));
+    }
+
+    #[test]
+    fn test_find_iter() {
+        let text = fragmented("abcabcab");
+
+        assert_eq!(starts(text.find_iter("ab", &(0..8))), vec![0, 3, 6]);
+        assert_eq!(starts(text.find_iter("ab", &(1..7))), vec![3]);
+        assert_eq!(starts(text.find_iter("cab", &(0..100))), vec![2, 5]);
+    }
+
+    #[test]
+    fn test_matches_dont_overlap() {
+        let text = fragmented("aaaaa");
+
+        assert_eq!(starts(text.find_iter("aa", &(0..5))), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_rfind() {
+        let text = fragmented("one two one two");
+
+        assert_eq!(text.rfind("one", 15), Some(8..11));
+        assert_eq!(text.rfind("one", 10), Some(0..3));
+        assert_eq!(text.rfind("two", 6), None);
+        assert_eq!(text.rfind("aa", 100), None);
+        assert_eq!(fragmented("aaa").rfind("aa", 3), Some(1..3));
+    }
+
+    #[test]
+    fn test_multibyte_patterns() {
+        let mut text = Document::from("ab\u{2028}cd\u{2028}");
+        text.insert(7, "é");
+
+        assert_eq!(text.find("\u{2028}", 5), Some(9..12));
+        assert_eq!(text.rfind("dé", 100), Some(6..9));
+    }
+
+    fn query_starts(
+        text: &Document,
+        pattern: &str,
+        match_case: bool,
+        whole_word: bool,
+    ) -> Vec<usize> {
+        let query = SearchQuery::new(
+            pattern,
+            SearchOptions {
+                match_case,
+                whole_word,
+            },
+        );
+
+        return text
+            .find_query_iter(&query, &(0..text.len()))
+            .map(|found| found.start)
+            .collect();
+    }
+
+    #[test]
+    fn test_ignoring_case() {
+        let text = fragmented("Straße STRASSE strasse ΣΊΣΥΦΟΣ σίσυφος");
+
+        assert_eq!(query_starts(&text, "strasse", false, false), vec![8, 16]);
+        assert_eq!(query_starts(&text, "STRAßE", false, false), vec![0]);
+        assert_eq!(query_starts(&text, "σίσυφοσ", false, false), vec![24, 39]);
+        assert_eq!(query_starts(&text, "strasse", true, false), vec![16]);
+    }
+
+    #[test]
+    fn test_whole_word() {
+        // The boundaries sit in neighbouring fragments.
+        let text = fragmented("cat concat cat_ cat, écat caté (cat)");
+
+        assert_eq!(query_starts(&text, "cat", true, true), vec![0, 16, 34]);
+        assert_eq!(query_starts(&text, "CAT", false, true), vec![0, 16, 34]);
+        assert_eq!(query_starts(&text, "cat", true, false).len(), 7);
+    }
+
+    #[test]
+    fn test_whole_word_finds_overlapping_candidates() {
+        let text = fragmented("ba a a");
+        let options = SearchOptions {
+            match_case: true,
+            whole_word: true,
+        };
+        let query = SearchQuery::new("a a", options);
+
+        // "a a" at 1 follows a "b", but the one overlapping it stands alone.
+        assert_eq!(text.find_query(&query, 0), Some(3..6));
+        assert_eq!(text.rfind_query(&query, 6), Some(3..6));
+        assert_eq!(text.rfind_query(&query, 5), None);
+    }
+
+    #[test]
+    fn test_reverse_query() {
+        let text = fragmented("Cat cat concat");
+        let options = SearchOptions {
+            match_case: false,
+            whole_word: true,
+        };
+        let query = SearchQuery::new("CAT", options);
+
+        assert_eq!(text.rfind_query(&query, 14), Some(4..7));
+        assert_eq!(text.rfind_query(&query, 6), Some(0..3));
+        assert!(SearchOptions::default().match_case);
+    }
+
+    #[test]
+    fn test_matches_agree_with_str() {
+        let source = "the cat sat on the mat with the other cat";
+        let text = fragmented(source);
+        let mut chunked = Document::from(&source[..20]);
+        chunked.insert(20, &source[20..]);
+
+        for pattern in ["the", "at", "cat", " the ", "t"] {
+            let expected: Vec<usize> = source.match_indices(pattern).map(|(i, _)| i).collect();
+
+            assert_eq!(starts(text.find_iter(pattern, &(0..100))), expected);
+            assert_eq!(starts(chunked.find_iter(pattern, &(0..100))), expected);
+            assert_eq!(
+                text.rfind(pattern, source.len()).map(|found| found.start),
+                source.rfind(pattern)
+            );
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use super::{Document, Regex};
+use std::ops::Range;
+
+enum Query {
+    Literal(String),
+    Regex(Box<Regex>),
+}
+
+// The matches of one search, kept up to date as the document is edited.
+// After an edit only the text around it is searched again; the matches
+// elsewhere are shifted into place.
+pub struct SearchSession {
+    query: Query,
+    matches: Vec<Range<usize>>,
+    // The document version the matches are for.
+    version: u64,
+}
+
+impl SearchSession {
+    pub fn literal(document: &Document, pattern: &str) -> Self {
+        return SearchSession::new(document, Query::Literal(pattern.to_owned()));
+    }
+
+    pub fn regex(document: &Document, regex: Regex) -> Self {
+        return SearchSession::new(document, Query::Regex(Box::new(regex)));
+    }
+
+    fn new(document: &Document, query: Query) -> Self {
+        let mut session = SearchSession {
+            query,
+            matches: vec![],
+            version: document.version(),
+        };
+        session.matches = session.search(document, 0).collect();
+
+        return session;
+    }
+
+    pub fn matches(&self) -> &[Range<usize>] {
+        return &self.matches;
+    }
+
+    // Catches up with the edits made to the document since the last call.
+    // Falls back to a full search if the document no longer remembers them.
+    pub fn refresh(&mut self, document: &Document) {
+        let changes = match document.changes_since(self.version) {
+            Some(changes) => changes,
+            None => {
+                self.matches = self.search(document, 0).collect();
+                self.version = document.version();
+                return;
+            }
+        };
+
+        // Where to search again, in terms of the current document.
+        let mut edited: Vec<Range<usize>> = vec![];
+
+        for change in changes {
+            let end = change.start + change.deleted;
+
+            // Matches touching an edit might not match any more, so they're
+            // searched for again along with the edited text.
+            let (kept, stale) = self
+                .matches
+                .drain(..)
+                .partition(|found| found.end < change.start || found.start > end);
+            self.matches = kept;
+            edited.extend(stale);
+
+            for found in self.matches.iter_mut().chain(edited.iter_mut()) {
+                *found = change.map_offset(found.start)..change.map_offset(found.end);
+            }
+
+            edited.push(change.start..change.start + change.inserted);
+        }
+
+        edited.sort_by_key(|range| range.start);
+
+        for range in merge_ranges(edited) {
+            self.resync(document, range);
+        }
+
+        self.version = document.version();
+    }
+
+    // Searches again from just before an edited range until the results
+    // line up with the old matches after it.
+    fn resync(&mut self, document: &Document, edited: Range<usize>) {
+        // How far back a match affected by the edit can start. A regex that
+        // can't match a linebreak only sees the edited line and the one
+        // character before it; one that can might start anywhere before.
+        let window_start = match &self.query {
+            Query::Literal(pattern) => edited.start.saturating_sub(pattern.len().saturating_sub(1)),
+            Query::Regex(regex) if regex.spans_lines() => 0,
+            Query::Regex(_) => document
+                .rfind("\n", edited.start)
+                .map_or(0, |linebreak| linebreak.end),
+        };
+
+        let first_stale = self
+            .matches
+            .partition_point(|found| found.start < window_start);
+        let previous_end = self.matches[..first_stale].last().map(|found| found.end);
+        let from = previous_end.map_or(window_start, |end| end.max(window_start));
+
+        let mut results = vec![];
+        let mut next_old = first_stale;
+        let mut synced_at = None;
+
+        for found in self.search(document, from) {
+            // A full search never puts an empty match right after another.
+            if found.is_empty() && Some(found.start) == previous_end {
+                continue;
+            }
+
+            while self
+                .matches
+                .get(next_old)
+                .is_some_and(|old| old.start < found.start)
+            {
+                next_old += 1;
+            }
+
+            if found.start >= edited.end && self.matches.get(next_old) == Some(&found) {
+                synced_at = Some(next_old);
+                break;
+            }
+
+            results.push(found);
+        }
+
+        let synced_at = synced_at.unwrap_or(self.matches.len());
+        self.matches.splice(first_stale..synced_at, results);
+    }
+
+    fn search<'a>(
+        &'a self,
+        document: &'a Document,
+        from: usize,
+    ) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
+        let range = from..document.len();
+
+        return match &self.query {
+            Query::Literal(pattern) => Box::new(document.find_iter(pattern, &range)),
+            Query::Regex(regex) => Box::new(
+                document
+                    .regex_find_iter(regex, &range)
+                    .map(|captures| captures.range()),
+            ),
+        };
+    }
+}
+
+// Joins sorted ranges that overlap or touch.
+fn merge_ranges(ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    let mut merged: Vec<Range<usize>> = vec![];
+
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    return merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh_matches(document: &Document, session: &SearchSession) -> Vec<Range<usize>> {
+        return session.search(document, 0).collect();
+    }
+
+    #[test]
+    fn test_initial_matches() {
+        let text = Document::from("one two one");
+        let session = SearchSession::literal(&text, "one");
+
+        assert_eq!(session.matches(), &[0..3, 8..11][..]);
+    }
+
+    #[test]
+    fn test_edits_shift_and_update_matches() {
+        let mut text = Document::from("one two one two one");
+        let mut session = SearchSession::literal(&text, "one");
+
+        text.insert(0, "zero ");
+        text.delete(&(13..16));
+        text.insert(8, "o");
+        session.refresh(&text);
+
+        assert_eq!(text.to_string(), "zero oneo two  two one");
+        assert_eq!(session.matches(), &[5..8, 19..22][..]);
+        assert_eq!(session.matches(), &fresh_matches(&text, &session)[..]);
+    }
+
+    #[test]
+    fn test_edits_create_matches() {
+        let mut text = Document::from("on tw on");
+        let mut session = SearchSession::literal(&text, "one");
+
+        text.insert(2, "e");
+        text.insert(9, "e");
+        session.refresh(&text);
+
+        assert_eq!(session.matches(), &[0..3, 7..10][..]);
+    }
+
+    #[test]
+    fn test_regex_session() {
+        let mut text = Document::from("let a = 1;\nlet bb = 22;\n");
+        let mut session = SearchSession::regex(&text, Regex::new(r"(?m)^let \w+").unwrap());
+
+        text.insert(16, "b");
+        text.insert(0, "// header\n");
+        text.delete(&(14..15));
+        session.refresh(&text);
+
+        assert_eq!(session.matches(), &fresh_matches(&text, &session)[..]);
+        assert_eq!(session.matches().first(), Some(&(20..27)));
+    }
+
+    #[test]
+    fn test_regex_matches_across_lines() {
+        let mut text = Document::from("foo\nbaz\nfoo\nbaz");
+        let mut session = SearchSession::regex(&text, Regex::new(r"foo\nbar").unwrap());
+
+        text.replace(&(4..7), "bar");
+        session.refresh(&text);
+        assert_eq!(session.matches().to_vec(), vec![0..7]);
+
+        text.replace(&(12..15), "bar");
+        text.insert(0, "x");
+        session.refresh(&text);
+        assert_eq!(session.matches(), &[1..8, 9..16][..]);
+        assert_eq!(session.matches(), &fresh_matches(&text, &session)[..]);
+    }
+
+    #[test]
+    fn test_stale_sessions_search_again() {
+        let mut text = Document::from("");
+        let mut session = SearchSession::literal(&text, "ab");
+
+        for _ in 0..2000 {
+            text.insert(text.len(), "ab");
+        }
+        session.refresh(&text);
+
+        assert_eq!(session.matches().len(), 2000);
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        assert_eq!(
+            merge_ranges(vec![0..2, 2..3, 5..7, 6..6, 9..10]),
+            vec![0..3, 5..7, 9..10]
+        );
+    }
+}
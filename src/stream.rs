@@ -0,0 +1,240 @@
+use super::indexed_string::IndexedString;
+use super::Document;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::{error::Error, fmt, str};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    // The input isn't UTF-8.
+    // (byte_offset_of_bad_sequence)
+    InvalidUtf8(usize),
+    // The progress callback asked to stop.
+    // (bytes_read)
+    Cancelled(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            LoadError::Io(error) => write!(fmt, "Failed to read document: {}", error),
+            LoadError::InvalidUtf8(byte) => {
+                write!(fmt, "Document isn't valid UTF-8 (at byte {})", byte)
+            }
+            LoadError::Cancelled(bytes) => {
+                write!(fmt, "Loading was cancelled after {} bytes", bytes)
+            }
+        };
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            LoadError::Io(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        return LoadError::Io(error);
+    }
+}
+
+impl Document {
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, LoadError> {
+        return Document::from_reader_with_progress(reader, |_| true);
+    }
+
+    // Reads the document in chunks, indexing linebreaks as they arrive.
+    // `progress` gets the total bytes read so far; returning `false` cancels.
+    pub fn from_reader_with_progress<R, F>(
+        mut reader: R,
+        mut progress: F,
+    ) -> Result<Self, LoadError>
+    where
+        R: Read,
+        F: FnMut(usize) -> bool,
+    {
+        let mut original = IndexedString::new();
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+
+        // Bytes of a character split across two reads.
+        let mut pending: Vec<u8> = vec![];
+        let mut bytes_read = 0;
+
+        loop {
+            let count = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(LoadError::Io(error)),
+            };
+
+            pending.extend_from_slice(&chunk[..count]);
+            let pending_offset = bytes_read - (pending.len() - count);
+            bytes_read += count;
+
+            let valid_bytes = match str::from_utf8(&pending) {
+                Ok(_) => pending.len(),
+                // Incomplete trailing character. Wait for more bytes.
+                Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                Err(error) => {
+                    return Err(LoadError::InvalidUtf8(pending_offset + error.valid_up_to()));
+                }
+            };
+
+            let text = str::from_utf8(&pending[..valid_bytes]).expect("Validated above");
+            original.append(text);
+            pending.drain(..valid_bytes);
+
+            if !progress(bytes_read) {
+                return Err(LoadError::Cancelled(bytes_read));
+            }
+        }
+
+        if !pending.is_empty() {
+            return Err(LoadError::InvalidUtf8(bytes_read - pending.len()));
+        }
+
+        return Ok(Document::from_original(original));
+    }
+
+    // Streams the document one fragment at a time, so memory use doesn't
+    // grow with the size of the text.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        return self.write_range_to(&(0..self.len()), writer);
+    }
+
+    // Writes the bytes in a range as they are, even if it starts or ends
+    // inside a character.
+    pub fn write_range_to<W: Write>(&self, range: &Range<usize>, writer: &mut W) -> io::Result<()> {
+        for chunk in self.byte_chunks(range) {
+            writer.write_all(chunk)?;
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its input a few bytes at a time.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.1.min(self.0.len()).min(buf.len());
+            buf[..count].copy_from_slice(&self.0[..count]);
+            self.0 = &self.0[count..];
+
+            return Ok(count);
+        }
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let text = Document::from_reader("first\nsecond".as_bytes()).unwrap();
+
+        assert_eq!(text.to_string(), "first\nsecond");
+        assert!(text.original.has_consistent_linebreaks());
+    }
+
+    #[test]
+    fn test_from_reader_joins_split_characters() {
+        let source = "naïve ☃ text\n";
+        let text = Document::from_reader(Trickle(source.as_bytes(), 3)).unwrap();
+
+        assert_eq!(text.to_string(), source);
+        assert_eq!(
+            text.original
+                .select_relative_linebreaks(0, source.len())
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_from_reader_reports_invalid_position() {
+        let result = Document::from_reader(Trickle(b"abc\xffdef", 2));
+
+        match result {
+            Err(LoadError::InvalidUtf8(byte)) => assert_eq!(byte, 3),
+            _ => panic!("Expected invalid UTF-8"),
+        }
+    }
+
+    #[test]
+    fn test_from_reader_reports_truncated_character() {
+        let result = Document::from_reader(&b"ab\xe2\x98"[..]);
+
+        match result {
+            Err(LoadError::InvalidUtf8(byte)) => assert_eq!(byte, 2),
+            _ => panic!("Expected invalid UTF-8"),
+        }
+    }
+
+    #[test]
+    fn test_from_reader_progress_and_cancellation() {
+        let mut reports = vec![];
+        let result = Document::from_reader_with_progress(Trickle(b"abcdefgh", 3), |bytes| {
+            reports.push(bytes);
+            bytes < 6
+        });
+
+        assert_eq!(reports, vec![3, 6]);
+        match result {
+            Err(LoadError::Cancelled(bytes)) => assert_eq!(bytes, 6),
+            _ => panic!("Expected cancellation"),
+        }
+    }
+
+    #[test]
+    fn test_write_to() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+        text.insert(13, " insertions");
+
+        let mut output = vec![];
+        text.write_to(&mut output).unwrap();
+
+        assert_eq!(output, b"original with insertions");
+    }
+
+    #[test]
+    fn test_write_range_to() {
+        let mut text = Document::from("original");
+        text.insert(8, " with");
+
+        let mut output = vec![];
+        text.write_range_to(&(4..11), &mut output).unwrap();
+
+        assert_eq!(output, b"inal wi");
+    }
+
+    #[test]
+    fn test_write_range_inside_characters() {
+        let mut text = Document::from("né");
+        text.insert(3, "☃");
+
+        let mut output = vec![];
+        text.write_range_to(&(2..5), &mut output).unwrap();
+
+        assert_eq!(output, "né☃".as_bytes()[2..5]);
+    }
+
+    #[test]
+    fn test_write_empty_document() {
+        let mut output = vec![];
+        Document::new().write_to(&mut output).unwrap();
+
+        assert!(output.is_empty());
+    }
+}